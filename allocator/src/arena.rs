@@ -0,0 +1,110 @@
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    cell::RefCell,
+    ptr::NonNull,
+};
+
+use crate::buddy::Stats;
+use crate::heap::Heap;
+
+/// Buddy allocator over a single memory range that implements
+/// [`Allocator`], so a subsystem can keep its allocations apart from the
+/// global heap with `Vec::new_in(&arena)` or `Box::new_in(value, &arena)`.
+///
+/// The arena is not `Sync`; wrap it in a lock to share it.
+#[derive(Debug)]
+pub struct Arena {
+    heap: RefCell<Heap<1>>,
+}
+
+impl Arena {
+    /// Creates an arena over `start..start + size`. The bookkeeping nodes are
+    /// kept at the start of the range.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be unused and writable, and must outlive the arena.
+    pub unsafe fn new(
+        start: usize,
+        size: usize,
+        min_block_size: usize,
+    ) -> Result<Self, &'static str> {
+        let mut heap = Heap::new(min_block_size);
+        heap.add_region(start, size)?;
+
+        Ok(Self {
+            heap: RefCell::new(heap),
+        })
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.heap.borrow().stats()
+    }
+}
+
+unsafe impl Allocator for Arena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let addr = self
+            .heap
+            .borrow_mut()
+            .alloc_layout(layout)
+            .map_err(|_| AllocError)?;
+
+        let ptr = NonNull::new(addr as *mut u8).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.heap
+            .borrow_mut()
+            .dealloc_layout(ptr.as_ptr() as usize, layout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{boxed::Box, vec::Vec};
+
+    const ARENA_SIZE: usize = 64 * 1024;
+    const MIN_BLOCK_SIZE: usize = 64;
+
+    #[repr(align(4096))]
+    struct Memory([u8; ARENA_SIZE]);
+
+    fn arena() -> Arena {
+        let memory = Box::leak(Box::new(Memory([0; ARENA_SIZE])));
+        unsafe { Arena::new(memory.0.as_ptr() as usize, ARENA_SIZE, MIN_BLOCK_SIZE).unwrap() }
+    }
+
+    #[test]
+    fn test_collections_in_arena() {
+        let arena = arena();
+        let reserved = arena.stats().reserved;
+
+        let mut numbers = Vec::new_in(&arena);
+        numbers.extend(0..100u64);
+        let boxed = Box::new_in([0u8; 3000], &arena);
+
+        let stats = arena.stats();
+        assert_eq!(stats.allocated, 1024 + 4096);
+        assert_eq!(numbers.iter().sum::<u64>(), 4950);
+        assert_eq!(boxed.as_ptr() as usize % 64, 0);
+
+        drop(numbers);
+        drop(boxed);
+        let stats = arena.stats();
+        assert_eq!(stats.allocated, 0);
+        assert_eq!(stats.reserved, reserved);
+    }
+
+    #[test]
+    fn test_arena_exhaustion() {
+        let arena = arena();
+
+        let mut numbers: Vec<u8, _> = Vec::new_in(&arena);
+        assert!(numbers.try_reserve(ARENA_SIZE).is_err());
+        assert!(numbers.try_reserve(ARENA_SIZE / 4).is_ok());
+    }
+}
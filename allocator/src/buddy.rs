@@ -1,4 +1,4 @@
-use core::ptr;
+use core::{fmt, ops::Index, ptr};
 
 #[cfg(test)]
 use std::println;
@@ -11,136 +11,459 @@ pub enum State {
     Free = 2,
 }
 
+/// State of a node as it is stored in the bookkeeping memory. Unlike
+/// [`State`], split nodes are told apart from allocated blocks, and the
+/// descendants of free and allocated blocks are never touched: they stay
+/// `Coalesced` until the block is split.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tag {
+    Coalesced = 0,
+    Allocated = 1,
+    Free = 2,
+    Split = 3,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    tag: Tag,
+    // Order of the largest free block in the subtree plus one (0 if there is
+    // none). Only kept up to date for split nodes.
+    longest: u8,
+}
+
+/// Bookkeeping nodes of the tree. Indexing yields the logical [`State`] of a
+/// node, i.e. split nodes and everything below an allocated block read as
+/// `Allocated`.
+struct Nodes(&'static mut [Node]);
+
+impl Nodes {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn tag(&self, idx: usize) -> Tag {
+        self.0[idx].tag
+    }
+
+    fn set_tag(&mut self, idx: usize, tag: Tag) {
+        self.0[idx].tag = tag;
+    }
+}
+
+impl Index<usize> for Nodes {
+    type Output = State;
+
+    fn index(&self, idx: usize) -> &State {
+        let mut ancestor = idx;
+        while let Some(parent) = BuddyAllocator::parent(ancestor) {
+            if self.tag(parent) == Tag::Allocated {
+                return &State::Allocated;
+            }
+            ancestor = parent;
+        }
+
+        match self.tag(idx) {
+            Tag::Coalesced => &State::Coalesced,
+            Tag::Free => &State::Free,
+            Tag::Allocated | Tag::Split => &State::Allocated,
+        }
+    }
+}
+
+impl fmt::Debug for Nodes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries((0..self.len()).map(|idx| self[idx]))
+            .finish()
+    }
+}
+
+/// Snapshot of the allocator state, see [`BuddyAllocator::stats`].
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub heap_size: usize,
+    pub min_block_size: usize,
+    /// Bytes in blocks handed out by the allocator
+    pub allocated: usize,
+    /// Bytes permanently taken out of the heap by [`BuddyAllocator::reserve`]
+    pub reserved: usize,
+    pub free: usize,
+    pub peak: usize,
+    pub largest_free: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    /// Number of free blocks of each order
+    pub free_blocks: [usize; usize::BITS as usize],
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "heap ::: size: {}, allocated: {}, reserved: {}, free: {}, peak: {}, largest free: {}, allocs: {}, frees: {}",
+            self.heap_size,
+            self.allocated,
+            self.reserved,
+            self.free,
+            self.peak,
+            self.largest_free,
+            self.allocs,
+            self.frees
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct BuddyAllocator {
-    nodes: &'static mut [State],
+    nodes: Nodes,
     heap_size: usize,
+    max_order: usize,
     pub min_block_size: usize,
+    allocated: usize,
+    reserved: usize,
+    peak: usize,
+    allocs: usize,
+    frees: usize,
 }
 
 impl BuddyAllocator {
+    /// Number of bookkeeping bytes needed to manage `heap_size` bytes.
+    pub const fn bookkeeping_size(heap_size: usize, min_block_size: usize) -> usize {
+        let max_order = (heap_size / min_block_size).next_power_of_two().ilog2();
+        (2usize.pow(max_order + 1) - 1) * core::mem::size_of::<Node>()
+    }
+
+    /// Creates an allocator for `heap_size` bytes, keeping its nodes at
+    /// `ptr`. The tree is rounded up to the next power of two and the tail
+    /// beyond `heap_size` is reserved, so no memory past the end is ever
+    /// handed out.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to [`BuddyAllocator::bookkeeping_size`] bytes of
+    /// writable memory that is not used for anything else.
     pub unsafe fn new(ptr: usize, heap_size: usize, min_block_size: usize) -> Self {
-        let max_order: usize = (heap_size / min_block_size).ilog2() as usize;
+        let num_blocks = heap_size / min_block_size;
+        let max_order: usize = num_blocks.next_power_of_two().ilog2() as usize;
         let num_nodes: usize = 2usize.pow(max_order as u32 + 1) - 1;
 
-        // This assumes that the memory is zeroed out
-        let nodes = &mut *ptr::slice_from_raw_parts_mut(ptr as *mut State, num_nodes);
+        ptr::write_bytes(ptr as *mut Node, 0, num_nodes);
+        let nodes = &mut *ptr::slice_from_raw_parts_mut(ptr as *mut Node, num_nodes);
 
-        nodes[0] = State::Free;
+        nodes[0].tag = Tag::Free;
 
-        Self {
-            nodes,
-            heap_size,
+        let mut allocator = Self {
+            nodes: Nodes(nodes),
+            heap_size: num_blocks * min_block_size,
+            max_order,
             min_block_size,
-        }
+            allocated: 0,
+            reserved: 0,
+            peak: 0,
+            allocs: 0,
+            frees: 0,
+        };
+
+        // The tail is not part of the heap, so it is not accounted as reserved
+        let tail = allocator.tree_size() - allocator.heap_size;
+        allocator
+            .claim_range(allocator.heap_size, tail)
+            .expect("Fresh tree must have room for its tail");
+
+        allocator
     }
 
-    fn split_ancestors(&mut self, idx: usize) {
-        match Self::parent(idx) {
-            None => return, // Root node
-            Some(next) => {
-                // Avoid messing up already split ancestors
-                if self.nodes[next] == State::Allocated {
-                    return;
-                }
+    /// Size of the memory covered by the tree, including the reserved tail.
+    fn tree_size(&self) -> usize {
+        self.min_block_size << self.max_order
+    }
 
-                self.split(next);
-                self.split_ancestors(next);
+    /// Offset of the block at `idx` from the start of the managed memory.
+    pub fn block_offset(&self, idx: usize) -> usize {
+        let order = self.order(idx);
+        let block_size = self.min_block_size << order;
+        (idx + 1 - (1 << (self.max_order - order))) * block_size
+    }
+
+    /// Index of the block of `block_size` bytes starting at `offset`.
+    pub fn block_index(&self, offset: usize, block_size: usize) -> usize {
+        self.order_start_index(block_size) + offset / block_size
+    }
+
+    /// Permanently allocates every block overlapping `offset..offset + size`.
+    pub fn reserve(&mut self, offset: usize, size: usize) -> Result<(), &'static str> {
+        self.reserved += self.claim_range(offset, size)?;
+        Ok(())
+    }
+
+    // Claims every block overlapping `offset..offset + size` and returns the
+    // number of bytes claimed.
+    fn claim_range(&mut self, offset: usize, size: usize) -> Result<usize, &'static str> {
+        let mut start = offset / self.min_block_size * self.min_block_size;
+        let end = (offset + size)
+            .next_multiple_of(self.min_block_size)
+            .min(self.tree_size());
+
+        while start < end {
+            // Take the largest aligned block that starts here and fits
+            let mut block_size = self.tree_size();
+            while !start.is_multiple_of(block_size) || start + block_size > end {
+                block_size /= 2;
             }
+
+            self.claim(self.block_index(start, block_size))?;
+            start += block_size;
         }
+
+        Ok(end - offset / self.min_block_size * self.min_block_size)
     }
 
-    fn split(&mut self, idx: usize) {
-        if let Some(left) = self.left(idx) {
-            self.nodes[left] = State::Free;
+    /// Allocates the block at `idx` if it is free or lies inside a free block.
+    pub fn claim_block(&mut self, idx: usize) -> Result<(), &'static str> {
+        self.claim(idx)?;
+        self.record_alloc(idx);
+        Ok(())
+    }
+
+    fn claim(&mut self, idx: usize) -> Result<(), &'static str> {
+        assert!(idx < self.nodes.len(), "Index {} out of bounds", idx);
+
+        let mut ancestor = idx;
+        while self.nodes.tag(ancestor) == Tag::Coalesced {
+            ancestor = Self::parent(ancestor).expect("Root cannot be coalesced");
         }
-        if let Some(right) = self.right(idx) {
-            self.nodes[right] = State::Free;
+
+        if self.nodes.tag(ancestor) != Tag::Free {
+            return Err("Block is not available");
         }
+
+        self.split_ancestors(idx);
+        self.nodes.set_tag(idx, Tag::Allocated);
+        self.update_ancestors(idx);
+
+        Ok(())
     }
 
-    fn mark_ancestors_allocated(&mut self, idx: usize) {
-        if idx == 0 {
-            return;
+    fn order(&self, idx: usize) -> usize {
+        self.max_order - (idx + 1).ilog2() as usize
+    }
+
+    fn block_size(&self, idx: usize) -> usize {
+        self.min_block_size << self.order(idx)
+    }
+
+    fn record_alloc(&mut self, idx: usize) {
+        self.allocated += self.block_size(idx);
+        self.peak = self.peak.max(self.allocated);
+        self.allocs += 1;
+    }
+
+    /// Takes a snapshot of the allocator usage. This walks every split node,
+    /// so it is meant for diagnostics rather than hot paths.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            heap_size: self.heap_size,
+            min_block_size: self.min_block_size,
+            allocated: self.allocated,
+            reserved: self.reserved,
+            free: 0,
+            peak: self.peak,
+            largest_free: 0,
+            allocs: self.allocs,
+            frees: self.frees,
+            free_blocks: [0; usize::BITS as usize],
+        };
+
+        self.count_free_blocks(0, &mut stats.free_blocks);
+
+        for (order, count) in stats.free_blocks.iter().enumerate() {
+            stats.free += count * (self.min_block_size << order);
+        }
+        if self.longest(0) > 0 {
+            stats.largest_free = self.min_block_size << (self.longest(0) - 1);
         }
 
-        let parent = Self::parent(idx).unwrap();
-        self.nodes[parent] = State::Allocated;
-        self.mark_ancestors_allocated(parent);
+        stats
     }
 
-    fn mark_descendants_allocated(&mut self, idx: usize) {
-        if let Some(left) = self.left(idx) {
-            self.nodes[left] = State::Allocated;
-            self.mark_descendants_allocated(left);
+    fn count_free_blocks(&self, idx: usize, free_blocks: &mut [usize]) {
+        match self.nodes.tag(idx) {
+            Tag::Free => free_blocks[self.order(idx)] += 1,
+            Tag::Split => {
+                self.count_free_blocks(2 * idx + 1, free_blocks);
+                self.count_free_blocks(2 * idx + 2, free_blocks);
+            }
+            Tag::Allocated | Tag::Coalesced => {}
         }
-        if let Some(right) = self.right(idx) {
-            self.nodes[right] = State::Allocated;
-            self.mark_descendants_allocated(right);
+    }
+
+    // Order of the largest free block in the subtree of `idx` plus one, or 0
+    // if nothing in the subtree can be allocated.
+    fn longest(&self, idx: usize) -> usize {
+        match self.nodes.tag(idx) {
+            Tag::Free => self.order(idx) + 1,
+            Tag::Split => self.nodes.0[idx].longest as usize,
+            Tag::Allocated | Tag::Coalesced => 0,
+        }
+    }
+
+    fn split_ancestors(&mut self, idx: usize) {
+        if let Some(next) = Self::parent(idx) {
+            // Avoid messing up already split ancestors
+            if self.nodes.tag(next) == Tag::Split {
+                return;
+            }
+
+            self.split(next);
+            self.split_ancestors(next);
         }
     }
 
-    fn mark_descendants_unavailable(&mut self, idx: usize) {
+    fn split(&mut self, idx: usize) {
         if let Some(left) = self.left(idx) {
-            self.nodes[left] = State::Coalesced;
-            self.mark_descendants_unavailable(left);
+            self.nodes.set_tag(left, Tag::Free);
         }
         if let Some(right) = self.right(idx) {
-            self.nodes[right] = State::Coalesced;
-            self.mark_descendants_unavailable(right);
+            self.nodes.set_tag(right, Tag::Free);
+        }
+    }
+
+    // Marks every ancestor of `idx` as split and refreshes the cached largest
+    // free order on the way up to the root.
+    fn update_ancestors(&mut self, idx: usize) {
+        let mut idx = idx;
+        while let Some(parent) = Self::parent(idx) {
+            let left = self.longest(2 * parent + 1);
+            let right = self.longest(2 * parent + 2);
+
+            self.nodes.0[parent] = Node {
+                tag: Tag::Split,
+                longest: left.max(right) as u8,
+            };
+            idx = parent;
         }
     }
 
+    /// Whether the block at `idx` itself was handed out, as opposed to lying
+    /// inside an allocated block or being free.
+    pub fn is_allocated(&self, idx: usize) -> bool {
+        self.nodes.tag(idx) == Tag::Allocated
+    }
+
     pub fn free_block(&mut self, idx: usize) {
         assert!(idx < self.nodes.len(), "Index {} out of bounds", idx);
         assert_eq!(
-            self.nodes[idx],
-            State::Allocated,
+            self.nodes.tag(idx),
+            Tag::Allocated,
             "Block not allocated, double free"
         );
 
-        self.nodes[idx] = State::Free;
+        self.allocated -= self.block_size(idx);
+        self.frees += 1;
+        self.release(idx);
+    }
+
+    /// Grows the allocated block at `idx` in place to `size` bytes by
+    /// claiming the free buddies following it. Returns the index of the grown
+    /// block, or `None` if the block does not start a large enough block whose
+    /// remaining buddies are all free.
+    pub fn grow_block(&mut self, idx: usize, size: usize) -> Option<usize> {
+        assert!(size.is_power_of_two(), "Size is not a power of 2");
+        assert_eq!(self.nodes.tag(idx), Tag::Allocated, "Block not allocated");
+
+        let old_size = self.block_size(idx);
+        let mut target = idx;
+        while self.block_size(target) < size {
+            // Only left children keep their start address when merged
+            if target == 0 || target % 2 == 0 {
+                return None;
+            }
+            if self.nodes.tag(target + 1) != Tag::Free {
+                return None;
+            }
+            target = Self::parent(target).unwrap();
+        }
+
+        // Everything below the grown block reads as allocated through it
+        let mut node = idx;
+        while node != target {
+            self.nodes.set_tag(node, Tag::Coalesced);
+            self.nodes.set_tag(node + 1, Tag::Coalesced);
+            node = Self::parent(node).unwrap();
+        }
+        self.nodes.set_tag(target, Tag::Allocated);
+        self.update_ancestors(target);
+
+        self.allocated += self.block_size(target) - old_size;
+        self.peak = self.peak.max(self.allocated);
+
+        Some(target)
+    }
+
+    /// Shrinks the allocated block at `idx` in place to `size` bytes by
+    /// splitting it and freeing the upper halves. Returns the index of the
+    /// remaining block, which starts at the same offset.
+    pub fn shrink_block(&mut self, idx: usize, size: usize) -> usize {
+        assert!(size.is_power_of_two(), "Size is not a power of 2");
+        assert!(size >= self.min_block_size, "Block size too small");
+        assert_eq!(self.nodes.tag(idx), Tag::Allocated, "Block not allocated");
+
+        let old_size = self.block_size(idx);
+        let mut node = idx;
+        while self.block_size(node) > size {
+            self.nodes.set_tag(node, Tag::Split);
+            self.nodes.set_tag(2 * node + 2, Tag::Free);
+            node = 2 * node + 1;
+        }
+        self.nodes.set_tag(node, Tag::Allocated);
+        self.update_ancestors(node);
+
+        self.allocated -= old_size - self.block_size(node);
+
+        node
+    }
+
+    fn release(&mut self, idx: usize) {
+        self.nodes.set_tag(idx, Tag::Free);
 
         if let Some(buddy) = self.buddy(idx) {
-            if self.nodes[buddy] == State::Free {
+            if self.nodes.tag(buddy) == Tag::Free {
                 return self.coalesce(idx);
             }
         }
 
-        // When freeing blocks > min_block_size, we need to mark descendants as
-        // unavailable, because they are marked as allocated. However, here we
-        // delay coalescing previously possibly allocated blocks, until we cannot
-        // coalesce parents anymore.
-        self.mark_descendants_unavailable(idx);
+        // Descendants of the block are still marked as coalesced from the time
+        // it was allocated, so only the ancestors need to learn about it.
+        self.update_ancestors(idx);
     }
 
     fn coalesce(&mut self, idx: usize) {
         let parent = Self::parent(idx).unwrap();
 
         if let Some(left) = self.left(parent) {
-            self.nodes[left] = State::Coalesced;
+            self.nodes.set_tag(left, Tag::Coalesced);
         }
         if let Some(right) = self.right(parent) {
-            self.nodes[right] = State::Coalesced;
+            self.nodes.set_tag(right, Tag::Coalesced);
         }
 
-        self.free_block(parent); // Recursively coalesce ancestors if possible
+        self.release(parent); // Recursively coalesce ancestors if possible
     }
 
     pub fn order_start_index(&self, block_size: usize) -> usize {
         assert!(block_size >= self.min_block_size, "Block size too small");
 
-        let max_order = (self.heap_size / self.min_block_size).ilog2();
         let order = (block_size / self.min_block_size).ilog2();
-        2usize.pow(max_order as u32 - order) - 1
+        2usize.pow(self.max_order as u32 - order) - 1
     }
 
     pub fn find_block(&mut self, size: usize) -> Result<usize, &str> {
         assert!(size.is_power_of_two(), "Size is not a power of 2");
-        assert!(
-            size <= self.heap_size,
-            "Requested size is greater than memory size"
-        );
+        if size > self.heap_size {
+            return Err("Requested size is greater than memory size");
+        }
 
         let search_start_idx = self.order_start_index(size);
         let end = 2 * search_start_idx + 1;
@@ -149,32 +472,43 @@ impl BuddyAllocator {
             "End index out of bounds, not enough nodes for heap size"
         );
 
-        let blocks = &self.nodes[search_start_idx..end];
-
-        if let Some(first_free) = blocks.iter().position(|b| *b == State::Free) {
-            let idx = search_start_idx + first_free;
-
-            self.mark_ancestors_allocated(idx);
-            self.mark_descendants_allocated(idx);
-            self.nodes[idx] = State::Allocated;
-
-            return Ok(idx);
+        let order = (size / self.min_block_size).ilog2() as usize;
+        if self.longest(0) <= order {
+            return Err("No block found for allocation");
         }
 
-        if let Some(first_to_split) = blocks.iter().position(|b| *b == State::Coalesced) {
-            let idx = search_start_idx + first_to_split;
-
-            assert_ne!(idx, 0, "Highest order block cannot be unavailable");
-
-            self.split_ancestors(idx);
-            self.mark_ancestors_allocated(idx);
-            self.mark_descendants_allocated(idx);
-            self.nodes[idx] = State::Allocated;
-
-            return Ok(idx);
+        // Walk down from the root towards a subtree that can hold the block.
+        // Once we pass a free block, everything below it is coalesced and the
+        // leftmost descendant of the requested order is as good as any.
+        let mut idx = 0;
+        while idx < search_start_idx {
+            let left = 2 * idx + 1;
+            let right = 2 * idx + 2;
+
+            idx = match self.nodes.tag(idx) {
+                Tag::Split => {
+                    let left_longest = self.longest(left);
+                    let right_longest = self.longest(right);
+
+                    // Prefer the tighter fit so that larger blocks stay intact
+                    if left_longest > order
+                        && (right_longest <= order || left_longest <= right_longest)
+                    {
+                        left
+                    } else {
+                        right
+                    }
+                }
+                _ => left,
+            };
         }
 
-        Err("No block found for allocation")
+        self.split_ancestors(idx);
+        self.nodes.set_tag(idx, Tag::Allocated);
+        self.update_ancestors(idx);
+        self.record_alloc(idx);
+
+        Ok(idx)
     }
 
     fn left(&self, idx: usize) -> Option<usize> {
@@ -357,13 +691,13 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Requested size is greater than memory size")]
     fn test_find_block_too_big() {
         static mut HEAP: [u8; 64] = [0; 64];
 
         let mut allocator =
             unsafe { BuddyAllocator::new(HEAP.as_ptr() as usize, HEAP_SIZE, MIN_BLOCK_SIZE) };
-        allocator.find_block(128).unwrap();
+        let block = allocator.find_block(128);
+        assert_matches!(block, Err("Requested size is greater than memory size"));
     }
 
     #[test]
@@ -512,4 +846,126 @@ mod tests {
 
         let idx = allocator.find_block(4);
     }
+
+    #[test]
+    fn test_prefer_exact_fit() {
+        static mut HEAP: [u8; 64] = [0; 64];
+
+        let mut allocator =
+            unsafe { BuddyAllocator::new(HEAP.as_ptr() as usize, HEAP_SIZE, MIN_BLOCK_SIZE) };
+
+        let a = allocator.find_block(8).unwrap();
+        let b = allocator.find_block(8).unwrap();
+        let c = allocator.find_block(8).unwrap();
+        allocator.free_block(a);
+        allocator.free_block(b);
+
+        // 16 byte block at index 3 is free again, but a new 8 byte block must
+        // still come from the half-used 16 byte block next to it.
+        let d = allocator.find_block(8).unwrap();
+        assert_eq!(
+            d,
+            allocator.buddy(c).unwrap(),
+            "Free 16 byte block was split"
+        );
+        assert_eq!(allocator.nodes[3], State::Free);
+    }
+
+    #[test]
+    fn test_allocate_every_min_block() {
+        static mut HEAP: [u8; 64] = [0; 64];
+
+        let mut allocator =
+            unsafe { BuddyAllocator::new(HEAP.as_ptr() as usize, HEAP_SIZE, MIN_BLOCK_SIZE) };
+
+        let start = allocator.order_start_index(MIN_BLOCK_SIZE);
+        for i in 0..HEAP_SIZE / MIN_BLOCK_SIZE {
+            assert_eq!(allocator.find_block(MIN_BLOCK_SIZE), Ok(start + i));
+        }
+        assert!(allocator.find_block(MIN_BLOCK_SIZE).is_err());
+
+        for i in (0..HEAP_SIZE / MIN_BLOCK_SIZE).rev() {
+            allocator.free_block(start + i);
+        }
+        assert_eq!(allocator.find_block(HEAP_SIZE), Ok(0));
+    }
+
+    #[test]
+    fn test_stats() {
+        static mut HEAP: [u8; 64] = [0; 64];
+
+        let mut allocator =
+            unsafe { BuddyAllocator::new(HEAP.as_ptr() as usize, HEAP_SIZE, MIN_BLOCK_SIZE) };
+
+        let a = allocator.find_block(16).unwrap();
+        let b = allocator.find_block(8).unwrap();
+        allocator.free_block(a);
+
+        let stats = allocator.stats();
+        assert_eq!(stats.allocated, 8);
+        assert_eq!(stats.peak, 24);
+        assert_eq!(stats.free, HEAP_SIZE - 8);
+        assert_eq!(stats.largest_free, 32);
+        assert_eq!((stats.allocs, stats.frees), (2, 1));
+        assert_eq!(&stats.free_blocks[..3], &[1, 1, 1]);
+
+        allocator.free_block(b);
+        let stats = allocator.stats();
+        assert_eq!(stats.free, HEAP_SIZE);
+        assert_eq!(stats.free_blocks[MAX_ORDER], 1);
+    }
+
+    #[test]
+    fn test_grow_block_in_place() {
+        static mut HEAP: [u8; 64] = [0; 64];
+
+        let mut allocator =
+            unsafe { BuddyAllocator::new(HEAP.as_ptr() as usize, HEAP_SIZE, MIN_BLOCK_SIZE) };
+
+        let a = allocator.find_block(8).unwrap();
+        assert_eq!(allocator.grow_block(a, 32), Some(1));
+        assert_descendants_allocated(1, &allocator, "Grown block");
+        assert_eq!(allocator.stats().allocated, 32);
+
+        // The grown block now sits next to a used buddy
+        let b = allocator.find_block(8).unwrap();
+        assert_eq!(allocator.grow_block(1, 64), None);
+
+        // Right children cannot grow without moving
+        let c = allocator.find_block(8).unwrap();
+        assert_eq!(c, allocator.buddy(b).unwrap());
+        assert_eq!(allocator.grow_block(c, 16), None);
+
+        allocator.free_block(1);
+        allocator.free_block(b);
+        allocator.free_block(c);
+        assert_eq!(allocator.nodes[0], State::Free);
+    }
+
+    #[test]
+    fn test_shrink_block_in_place() {
+        static mut HEAP: [u8; 64] = [0; 64];
+
+        let mut allocator =
+            unsafe { BuddyAllocator::new(HEAP.as_ptr() as usize, HEAP_SIZE, MIN_BLOCK_SIZE) };
+
+        let a = allocator.find_block(64).unwrap();
+        let a = allocator.shrink_block(a, 16);
+        assert_eq!(a, 3);
+        assert_eq!(allocator.block_offset(a), 0);
+
+        let stats = allocator.stats();
+        assert_eq!(stats.allocated, 16);
+        assert_eq!(stats.largest_free, 32);
+        assert_eq!(&stats.free_blocks[..3], &[0, 1, 1]);
+
+        // The freed halves are allocatable again
+        assert_eq!(allocator.find_block(32), Ok(2));
+        assert_eq!(allocator.find_block(16), Ok(4));
+
+        allocator.free_block(a);
+        allocator.free_block(2);
+        allocator.free_block(4);
+        assert_eq!(allocator.nodes[0], State::Free);
+    }
 }
@@ -0,0 +1,71 @@
+use core::alloc::Layout;
+
+/// Allocator that hands out memory by bumping a pointer and never frees
+/// anything. It serves allocations before the buddy heap is set up, which
+/// then takes over the memory and keeps the used prefix reserved.
+#[derive(Debug)]
+pub struct BumpAllocator {
+    start: usize,
+    end: usize,
+    next: usize,
+}
+
+impl BumpAllocator {
+    pub const fn new(start: usize, size: usize) -> Self {
+        Self {
+            start,
+            end: start + size,
+            next: start,
+        }
+    }
+
+    pub fn alloc(&mut self, layout: Layout) -> Option<usize> {
+        let addr = self.next.checked_next_multiple_of(layout.align())?;
+        let end = addr.checked_add(layout.size())?;
+        if end > self.end {
+            return None;
+        }
+
+        self.next = end;
+        Some(addr)
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Number of bytes handed out so far, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.next - self.start
+    }
+
+    /// Whether `addr` was handed out by this allocator.
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.start..self.next).contains(&addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bump() {
+        let mut bump = BumpAllocator::new(0x1000, 64);
+
+        let a = bump.alloc(Layout::from_size_align(3, 1).unwrap());
+        let b = bump.alloc(Layout::from_size_align(16, 16).unwrap());
+        assert_eq!(a, Some(0x1000));
+        assert_eq!(b, Some(0x1010));
+        assert_eq!(bump.used(), 32);
+        assert!(bump.contains(0x1002));
+        assert!(!bump.contains(0x1020));
+
+        assert_eq!(bump.alloc(Layout::from_size_align(33, 1).unwrap()), None);
+        assert_eq!(
+            bump.alloc(Layout::from_size_align(32, 1).unwrap()),
+            Some(0x1020)
+        );
+        assert_eq!(bump.used(), 64);
+    }
+}
@@ -0,0 +1,183 @@
+//! Red zones and poisoning for debugging heap corruption.
+//!
+//! Every allocation is surrounded by two red zones filled with
+//! [`RED_ZONE_BYTE`], and its contents start out as [`ALLOC_POISON`]. On free,
+//! the red zones are checked and the whole allocation, red zones included, is
+//! overwritten with [`FREE_POISON`], which makes use after free show up as
+//! recognizable garbage and double frees as poisoned red zones.
+
+use core::{alloc::Layout, fmt, mem::size_of, ptr, slice};
+
+pub const RED_ZONE_SIZE: usize = 16;
+pub const RED_ZONE_BYTE: u8 = 0xfd;
+pub const ALLOC_POISON: u8 = 0xcd;
+pub const FREE_POISON: u8 = 0xdd;
+
+// Free lists of the underlying allocators link through the first word of a
+// freed allocation, so it is never checked.
+const LINK_SIZE: usize = size_of::<usize>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    DoubleFree,
+    /// Byte at `offset` before the allocation was overwritten
+    Underflow {
+        offset: usize,
+    },
+    /// Byte at `offset` after the end of the allocation was overwritten
+    Overflow {
+        offset: usize,
+    },
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::DoubleFree => write!(f, "double free"),
+            Corruption::Underflow { offset } => {
+                write!(f, "red zone overwritten {} bytes before allocation", offset)
+            }
+            Corruption::Overflow { offset } => {
+                write!(f, "red zone overwritten {} bytes after allocation", offset)
+            }
+        }
+    }
+}
+
+/// Layout of `layout` wrapped in red zones.
+#[derive(Debug, Clone, Copy)]
+pub struct Guarded {
+    /// Layout requested by the caller
+    pub layout: Layout,
+    /// Layout actually allocated
+    pub outer: Layout,
+    /// Offset of the caller's memory in the outer allocation. The front red
+    /// zone is grown to keep the alignment of `layout`.
+    pub offset: usize,
+}
+
+impl Guarded {
+    pub fn new(layout: Layout) -> Option<Self> {
+        let offset = RED_ZONE_SIZE.max(layout.align());
+        let size = offset
+            .checked_add(layout.size())?
+            .checked_add(RED_ZONE_SIZE)?;
+        let outer = Layout::from_size_align(size, layout.align()).ok()?;
+
+        Some(Self {
+            layout,
+            outer,
+            offset,
+        })
+    }
+
+    /// Fills the red zones and poisons the memory of a fresh outer allocation
+    /// at `outer`, returning the address handed to the caller.
+    ///
+    /// # Safety
+    ///
+    /// `outer` must point to a writable allocation of `self.outer`.
+    pub unsafe fn arm(&self, outer: usize) -> usize {
+        let inner = outer + self.offset;
+        ptr::write_bytes(outer as *mut u8, RED_ZONE_BYTE, self.offset);
+        ptr::write_bytes(inner as *mut u8, ALLOC_POISON, self.layout.size());
+        ptr::write_bytes(
+            (inner + self.layout.size()) as *mut u8,
+            RED_ZONE_BYTE,
+            RED_ZONE_SIZE,
+        );
+        inner
+    }
+
+    /// Start of the outer allocation of the caller's memory at `inner`.
+    pub fn outer(&self, inner: usize) -> usize {
+        inner - self.offset
+    }
+
+    /// Checks the red zones around `inner`.
+    ///
+    /// # Safety
+    ///
+    /// `inner` must have been returned by [`Guarded::arm`] with the same
+    /// layout, and the outer allocation must still be readable.
+    pub unsafe fn check(&self, inner: usize) -> Result<(), Corruption> {
+        let outer = self.outer(inner);
+        let front = slice::from_raw_parts(outer as *const u8, self.offset);
+        let back = slice::from_raw_parts((inner + self.layout.size()) as *const u8, RED_ZONE_SIZE);
+
+        let front = &front[LINK_SIZE..];
+        if front.iter().all(|&byte| byte == FREE_POISON) {
+            return Err(Corruption::DoubleFree);
+        }
+        if let Some(pos) = front.iter().rposition(|&byte| byte != RED_ZONE_BYTE) {
+            let offset = front.len() - pos;
+            return Err(Corruption::Underflow { offset });
+        }
+        if let Some(offset) = back.iter().position(|&byte| byte != RED_ZONE_BYTE) {
+            return Err(Corruption::Overflow { offset });
+        }
+
+        Ok(())
+    }
+
+    /// Overwrites the whole outer allocation of `inner` with
+    /// [`FREE_POISON`].
+    ///
+    /// # Safety
+    ///
+    /// `inner` must have been returned by [`Guarded::arm`] with the same
+    /// layout and must not be used afterwards.
+    pub unsafe fn poison(&self, inner: usize) {
+        ptr::write_bytes(self.outer(inner) as *mut u8, FREE_POISON, self.outer.size());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec;
+
+    fn alloc(guarded: &Guarded) -> usize {
+        let memory = vec![0u8; guarded.outer.size() + guarded.outer.align()].leak();
+        let outer = (memory.as_ptr() as usize).next_multiple_of(guarded.outer.align());
+        unsafe { guarded.arm(outer) }
+    }
+
+    #[test]
+    fn test_red_zones() {
+        let guarded = Guarded::new(Layout::from_size_align(24, 8).unwrap()).unwrap();
+        let inner = alloc(&guarded);
+        assert_eq!(guarded.offset, RED_ZONE_SIZE);
+        assert_eq!(unsafe { *(inner as *const u8) }, ALLOC_POISON);
+        assert_eq!(unsafe { guarded.check(inner) }, Ok(()));
+
+        unsafe { *((inner + 24) as *mut u8) = 0 };
+        assert_eq!(
+            unsafe { guarded.check(inner) },
+            Err(Corruption::Overflow { offset: 0 })
+        );
+
+        unsafe { *((inner + 24) as *mut u8) = RED_ZONE_BYTE };
+        unsafe { *((inner - 2) as *mut u8) = 0 };
+        assert_eq!(
+            unsafe { guarded.check(inner) },
+            Err(Corruption::Underflow { offset: 2 })
+        );
+    }
+
+    #[test]
+    fn test_double_free() {
+        let guarded = Guarded::new(Layout::from_size_align(8, 64).unwrap()).unwrap();
+        let inner = alloc(&guarded);
+        assert_eq!(inner % 64, 0);
+
+        unsafe {
+            guarded.poison(inner);
+            // A free list link written into the freed memory
+            *(guarded.outer(inner) as *mut usize) = 0x1234;
+        }
+        assert_eq!(unsafe { guarded.check(inner) }, Err(Corruption::DoubleFree));
+        assert_eq!(unsafe { *(inner as *const u8) }, FREE_POISON);
+    }
+}
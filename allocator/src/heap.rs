@@ -0,0 +1,457 @@
+use core::alloc::Layout;
+
+use crate::buddy::{BuddyAllocator, Stats};
+use crate::slab::BlockSource;
+
+/// A contiguous range of memory managed by its own buddy tree.
+#[derive(Debug)]
+pub struct Region {
+    start: usize,
+    size: usize,
+    allocator: BuddyAllocator,
+}
+
+impl Region {
+    fn contains(&self, addr: usize) -> bool {
+        (self.start..self.start + self.size).contains(&addr)
+    }
+
+    // Blocks are only aligned to their size relative to the start of the
+    // region. If the start itself is not aligned to `align`, a block of twice
+    // the size is taken instead, which always contains an aligned address
+    // with `size` bytes behind it.
+    fn block_size(&self, size: usize, align: usize) -> usize {
+        let size = size.max(align);
+        if self.start.is_multiple_of(align) {
+            size
+        } else {
+            2 * size
+        }
+    }
+
+    fn block_index(&self, addr: usize, block_size: usize) -> usize {
+        self.allocator.block_index(addr - self.start, block_size)
+    }
+}
+
+/// Heap made of up to `N` independent regions, each covered by a buddy tree.
+#[derive(Debug)]
+pub struct Heap<const N: usize> {
+    regions: [Option<Region>; N],
+    pub min_block_size: usize,
+    allocated: usize,
+    peak: usize,
+}
+
+impl<const N: usize> Heap<N> {
+    pub const fn new(min_block_size: usize) -> Self {
+        Self {
+            regions: [const { None }; N],
+            min_block_size,
+            allocated: 0,
+            peak: 0,
+        }
+    }
+
+    /// Adds `start..start + size` to the heap, keeping the bookkeeping nodes
+    /// at `bookkeeping` outside of the region.
+    ///
+    /// # Safety
+    ///
+    /// The region must be unused memory, and `bookkeeping` must satisfy the
+    /// requirements of [`BuddyAllocator::new`].
+    pub unsafe fn add_region_with_bookkeeping(
+        &mut self,
+        bookkeeping: usize,
+        start: usize,
+        size: usize,
+    ) -> Result<(), &'static str> {
+        let slot = self
+            .regions
+            .iter_mut()
+            .find(|region| region.is_none())
+            .ok_or("No free region slot")?;
+
+        *slot = Some(Region {
+            start,
+            size,
+            allocator: BuddyAllocator::new(bookkeeping, size, self.min_block_size),
+        });
+
+        Ok(())
+    }
+
+    /// Adds `start..start + size` to the heap. The bookkeeping nodes are
+    /// placed at the start of the region and reserved.
+    ///
+    /// # Safety
+    ///
+    /// The region must be unused, writable memory.
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) -> Result<(), &'static str> {
+        self.add_region_in_use(start, size, 0)
+    }
+
+    /// Adds `start..start + size` to the heap when its first `used` bytes are
+    /// already taken, e.g. by a boot allocator. The prefix is reserved for
+    /// good, followed by the bookkeeping nodes.
+    ///
+    /// # Safety
+    ///
+    /// The region past `used` must be unused, writable memory.
+    pub unsafe fn add_region_in_use(
+        &mut self,
+        start: usize,
+        size: usize,
+        used: usize,
+    ) -> Result<(), &'static str> {
+        let bookkeeping = BuddyAllocator::bookkeeping_size(size, self.min_block_size);
+        if used + bookkeeping >= size {
+            return Err("Region too small to hold its own bookkeeping");
+        }
+
+        self.add_region_with_bookkeeping(start + used, start, size)?;
+
+        let region = self.regions.iter_mut().flatten().last().unwrap();
+        region.allocator.reserve(0, used + bookkeeping)
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter().flatten()
+    }
+
+    /// Allocates a block of `size` bytes, which must be a power of two and at
+    /// least `min_block_size`. Regions are tried in the order they were added.
+    /// The block is aligned to `size` relative to the start of its region.
+    pub fn alloc(&mut self, size: usize) -> Result<usize, &'static str> {
+        self.alloc_aligned(size, 1)
+    }
+
+    /// Allocates `size` bytes at an address aligned to `align`, both powers
+    /// of two. Unlike [`Heap::alloc`], the alignment holds for the absolute
+    /// address, whatever the alignment of the region start.
+    pub fn alloc_aligned(&mut self, size: usize, align: usize) -> Result<usize, &'static str> {
+        for region in self.regions.iter_mut().flatten() {
+            let block_size = region.block_size(size, align);
+            if block_size > region.size {
+                continue;
+            }
+
+            if let Ok(idx) = region.allocator.find_block(block_size) {
+                self.allocated += block_size;
+                self.peak = self.peak.max(self.allocated);
+                let block = region.start + region.allocator.block_offset(idx);
+                return Ok(block.next_multiple_of(align));
+            }
+        }
+
+        Err("No block found for allocation")
+    }
+
+    /// Size of the block serving `layout`, or `None` if it cannot be
+    /// represented. Alignment is left to [`Heap::alloc_aligned`], so only the
+    /// size is rounded up to a power of two and at least `min_block_size`.
+    pub fn layout_size(&self, layout: Layout) -> Option<usize> {
+        let size = layout.size().checked_next_power_of_two()?;
+        Some(size.max(self.min_block_size))
+    }
+
+    /// Allocates memory for `layout`.
+    pub fn alloc_layout(&mut self, layout: Layout) -> Result<usize, &'static str> {
+        let size = self
+            .layout_size(layout)
+            .ok_or("Requested size is greater than memory size")?;
+        self.alloc_aligned(size, layout.align())
+    }
+
+    /// Frees memory previously returned by [`Heap::alloc_layout`] with the
+    /// same `layout`.
+    pub fn dealloc_layout(&mut self, addr: usize, layout: Layout) {
+        let size = self.layout_size(layout).unwrap();
+        self.dealloc_aligned(addr, size, layout.align())
+    }
+
+    /// Frees the block of `size` bytes at `addr` previously returned by
+    /// [`Heap::alloc`].
+    pub fn dealloc(&mut self, addr: usize, size: usize) {
+        self.dealloc_aligned(addr, size, 1)
+    }
+
+    /// Frees memory previously returned by [`Heap::alloc_aligned`] with the
+    /// same `size` and `align`.
+    pub fn dealloc_aligned(&mut self, addr: usize, size: usize, align: usize) {
+        let region = self.region_mut(addr);
+        let block_size = region.block_size(size, align);
+        let idx = region.block_index(addr, block_size);
+        assert!(
+            region.allocator.is_allocated(idx),
+            "Double free of {:#x} ({} bytes, block {})",
+            addr,
+            size,
+            idx
+        );
+        region.allocator.free_block(idx);
+        self.allocated -= block_size;
+    }
+
+    /// Index of the block in its region's tree that holds the memory at
+    /// `addr`, allocated with `size` and `align`.
+    pub fn block_index(&self, addr: usize, size: usize, align: usize) -> usize {
+        let region = self.region(addr);
+        region.block_index(addr, region.block_size(size, align))
+    }
+
+    /// Whether the memory at `addr`, allocated with `size` and `align`, is
+    /// still allocated.
+    pub fn is_allocated(&self, addr: usize, size: usize, align: usize) -> bool {
+        let region = self.region(addr);
+        let idx = region.block_index(addr, region.block_size(size, align));
+        region.allocator.is_allocated(idx)
+    }
+
+    /// Resizes the memory at `addr` from `old_size` to `new_size` bytes
+    /// without moving it, by claiming free buddies or splitting the block.
+    /// Returns `false` if that is not possible and the memory must be moved.
+    pub fn resize_in_place(
+        &mut self,
+        addr: usize,
+        old_size: usize,
+        new_size: usize,
+        align: usize,
+    ) -> bool {
+        let region = self.region_mut(addr);
+        let old_block_size = region.block_size(old_size, align);
+        let new_block_size = region.block_size(new_size, align);
+        let idx = region.block_index(addr, old_block_size);
+
+        // Over-aligned blocks hand out an address inside the block, which
+        // would be computed differently for the new size.
+        if region.start + region.allocator.block_offset(idx) != addr {
+            return false;
+        }
+
+        if new_block_size > old_block_size {
+            if region.allocator.grow_block(idx, new_block_size).is_none() {
+                return false;
+            }
+            self.allocated += new_block_size - old_block_size;
+            self.peak = self.peak.max(self.allocated);
+        } else if new_block_size < old_block_size {
+            region.allocator.shrink_block(idx, new_block_size);
+            self.allocated -= old_block_size - new_block_size;
+        }
+
+        true
+    }
+
+    fn region(&self, addr: usize) -> &Region {
+        self.regions()
+            .find(|region| region.contains(addr))
+            .expect("Address does not belong to any heap region")
+    }
+
+    fn region_mut(&mut self, addr: usize) -> &mut Region {
+        self.regions
+            .iter_mut()
+            .flatten()
+            .find(|region| region.contains(addr))
+            .expect("Address does not belong to any heap region")
+    }
+
+    /// Combined usage of all regions. Free blocks are counted per order of
+    /// `min_block_size`, and the peak is tracked across the whole heap.
+    pub fn stats(&self) -> Stats {
+        let mut total = Stats {
+            heap_size: 0,
+            min_block_size: self.min_block_size,
+            allocated: 0,
+            reserved: 0,
+            free: 0,
+            peak: self.peak,
+            largest_free: 0,
+            allocs: 0,
+            frees: 0,
+            free_blocks: [0; usize::BITS as usize],
+        };
+
+        for region in self.regions() {
+            let stats = region.allocator.stats();
+            total.heap_size += stats.heap_size;
+            total.allocated += stats.allocated;
+            total.reserved += stats.reserved;
+            total.free += stats.free;
+            total.largest_free = total.largest_free.max(stats.largest_free);
+            total.allocs += stats.allocs;
+            total.frees += stats.frees;
+            for (sum, count) in total.free_blocks.iter_mut().zip(stats.free_blocks) {
+                *sum += count;
+            }
+        }
+
+        total
+    }
+}
+
+impl<const N: usize> BlockSource for Heap<N> {
+    fn alloc_block(&mut self, size: usize) -> Option<usize> {
+        self.alloc(size).ok()
+    }
+
+    fn free_block(&mut self, addr: usize, size: usize) {
+        self.dealloc(addr, size)
+    }
+
+    fn block_start(&self, addr: usize, size: usize) -> usize {
+        let region = self.region(addr);
+
+        // Blocks are aligned to their size relative to the start of the region
+        region.start + (addr - region.start) / size * size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN_BLOCK_SIZE: usize = 8;
+
+    #[test]
+    fn test_non_power_of_two_region() {
+        static mut BOOKKEEPING: [u8; 64] = [0; 64];
+        static mut MEMORY: [u8; 48] = [0; 48];
+
+        let mut heap = Heap::<1>::new(MIN_BLOCK_SIZE);
+        let start = unsafe { MEMORY.as_ptr() as usize };
+        unsafe {
+            heap.add_region_with_bookkeeping(BOOKKEEPING.as_ptr() as usize, start, 48)
+                .unwrap();
+        }
+
+        // 32 + 16 bytes fit, the reserved 16 byte tail must never be returned
+        assert_eq!(heap.alloc(32), Ok(start));
+        assert_eq!(heap.alloc(16), Ok(start + 32));
+        assert!(heap.alloc(8).is_err());
+
+        heap.dealloc(start + 32, 16);
+        assert_eq!(heap.alloc(8), Ok(start + 32));
+    }
+
+    #[test]
+    fn test_self_hosted_region() {
+        static mut MEMORY: [u8; 256] = [0; 256];
+
+        let mut heap = Heap::<1>::new(MIN_BLOCK_SIZE);
+        let start = unsafe { MEMORY.as_ptr() as usize };
+        unsafe { heap.add_region(start, 256).unwrap() };
+
+        // 63 nodes of bookkeeping occupy the first 128 bytes
+        assert_eq!(heap.alloc(128), Ok(start + 128));
+        assert!(heap.alloc(8).is_err());
+    }
+
+    #[test]
+    fn test_region_in_use() {
+        static mut MEMORY: [u8; 512] = [0; 512];
+
+        let mut heap = Heap::<1>::new(MIN_BLOCK_SIZE);
+        let start = unsafe { MEMORY.as_ptr() as usize };
+        unsafe {
+            MEMORY[0] = 0xaa;
+            heap.add_region_in_use(start, 512, 100).unwrap();
+        }
+
+        // The used prefix and 127 nodes of 2 bytes behind it take up 360 bytes
+        assert_eq!(heap.stats().reserved, 360);
+        assert_eq!(heap.alloc(128), Ok(start + 384));
+        assert_eq!(heap.alloc(16), Ok(start + 368));
+        assert_eq!(heap.alloc(8), Ok(start + 360));
+        assert!(heap.alloc(8).is_err());
+        assert_eq!(unsafe { MEMORY[0] }, 0xaa, "Used prefix overwritten");
+    }
+
+    #[test]
+    fn test_multiple_regions() {
+        static mut BOOKKEEPING: [u8; 64] = [0; 64];
+        static mut FIRST: [u8; 32] = [0; 32];
+        static mut SECOND: [u8; 256] = [0; 256];
+
+        let mut heap = Heap::<2>::new(MIN_BLOCK_SIZE);
+        let first = unsafe { FIRST.as_ptr() as usize };
+        let second = unsafe { SECOND.as_ptr() as usize };
+        unsafe {
+            heap.add_region_with_bookkeeping(BOOKKEEPING.as_ptr() as usize, first, 32)
+                .unwrap();
+            heap.add_region(second, 256).unwrap();
+        }
+
+        assert_eq!(heap.alloc(32), Ok(first));
+        assert_eq!(heap.alloc(64), Ok(second + 128));
+
+        heap.dealloc(first, 32);
+        assert_eq!(heap.alloc(16), Ok(first));
+
+        let stats = heap.stats();
+        assert_eq!(stats.heap_size, 32 + 256);
+        assert_eq!(stats.allocated, 16 + 64);
+        assert_eq!(stats.reserved, 128);
+        assert_eq!(stats.peak, 32 + 64);
+        assert_eq!(stats.free, 16 + 64);
+        assert_eq!(stats.largest_free, 64);
+    }
+
+    #[test]
+    fn test_alignment_of_unaligned_region() {
+        static mut BOOKKEEPING: [u8; 64] = [0; 64];
+
+        #[repr(align(64))]
+        struct Memory([u8; 256]);
+        static mut MEMORY: Memory = Memory([0; 256]);
+
+        // Region starts 8 bytes into a 64 byte aligned buffer
+        let mut heap = Heap::<1>::new(MIN_BLOCK_SIZE);
+        let start = unsafe { MEMORY.0.as_ptr() as usize } + 8;
+        unsafe {
+            heap.add_region_with_bookkeeping(BOOKKEEPING.as_ptr() as usize, start, 128)
+                .unwrap();
+        }
+
+        let addr = heap.alloc_aligned(16, 16).unwrap();
+        assert_eq!(addr % 16, 0, "Address not aligned");
+        assert_eq!(heap.stats().allocated, 32);
+
+        // Alignment larger than the size
+        let big = heap.alloc_aligned(8, 32).unwrap();
+        assert_eq!(big % 32, 0, "Address not aligned");
+        assert!(big + 8 <= start + 128);
+
+        heap.dealloc_aligned(addr, 16, 16);
+        heap.dealloc_aligned(big, 8, 32);
+        assert_eq!(heap.stats().allocated, 0);
+    }
+
+    #[test]
+    fn test_resize_in_place() {
+        static mut BOOKKEEPING: [u8; 64] = [0; 64];
+        static mut MEMORY: [u8; 64] = [0; 64];
+
+        let mut heap = Heap::<1>::new(MIN_BLOCK_SIZE);
+        let start = unsafe { MEMORY.as_ptr() as usize };
+        unsafe {
+            heap.add_region_with_bookkeeping(BOOKKEEPING.as_ptr() as usize, start, 64)
+                .unwrap();
+        }
+
+        let addr = heap.alloc(8).unwrap();
+        assert!(heap.resize_in_place(addr, 8, 32, 1));
+        assert_eq!(heap.stats().allocated, 32);
+        assert_eq!(heap.alloc(32), Ok(start + 32));
+        assert!(!heap.resize_in_place(addr, 32, 64, 1));
+
+        assert!(heap.resize_in_place(addr, 32, 16, 1));
+        assert_eq!(heap.alloc(16), Ok(start + 16));
+
+        heap.dealloc(addr, 16);
+        heap.dealloc(start + 16, 16);
+        heap.dealloc(start + 32, 32);
+        assert_eq!(heap.stats().allocated, 0);
+    }
+}
@@ -1,6 +1,13 @@
 #![cfg_attr(target_os = "none", no_std)]
+#![feature(allocator_api)]
 #![feature(assert_matches)]
 #![feature(const_mut_refs)]
 #![feature(const_slice_from_raw_parts_mut)]
 
+pub mod arena;
 pub mod buddy;
+pub mod bump;
+#[cfg(feature = "debug-alloc")]
+pub mod debug;
+pub mod heap;
+pub mod slab;
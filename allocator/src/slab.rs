@@ -0,0 +1,400 @@
+use core::{
+    alloc::Layout,
+    fmt,
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ptr::{self, NonNull},
+};
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Sizes served by [`SlabAllocator`]. Anything larger goes straight to the
+/// buddy allocator.
+pub const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Provider of the power-of-two blocks that slabs are carved from.
+pub trait BlockSource {
+    /// Allocates a block of `size` bytes, aligned to `size` within its region.
+    fn alloc_block(&mut self, size: usize) -> Option<usize>;
+
+    fn free_block(&mut self, addr: usize, size: usize);
+
+    /// Start of the block of `size` bytes that contains `addr`.
+    fn block_start(&self, addr: usize, size: usize) -> usize;
+}
+
+// Lives at the start of every slab
+struct SlabHeader {
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Cache of equally sized objects. Each slab is a block obtained from a
+/// [`BlockSource`] with a small header followed by the objects. Free objects
+/// are linked through their first word.
+#[derive(Debug)]
+pub struct SlabCache {
+    object_size: usize,
+    align: usize,
+    slab_size: usize,
+    // Slabs with at least one free object. Full slabs are not linked anywhere
+    // and are found again through the address of a freed object.
+    partial: *mut SlabHeader,
+    slabs: usize,
+    in_use: usize,
+}
+
+// The cache exclusively owns its slabs
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    pub const fn new(object_size: usize, align: usize) -> Self {
+        let align = if align > align_of::<FreeObject>() {
+            align
+        } else {
+            align_of::<FreeObject>()
+        };
+        let object_size = if object_size > size_of::<FreeObject>() {
+            object_size
+        } else {
+            size_of::<FreeObject>()
+        }
+        .next_multiple_of(align);
+
+        // Keep the header overhead below an eighth of the slab
+        let slab_size = (object_size * 8).next_power_of_two();
+        let slab_size = if slab_size > PAGE_SIZE {
+            slab_size
+        } else {
+            PAGE_SIZE
+        };
+
+        Self {
+            object_size,
+            align,
+            slab_size,
+            partial: ptr::null_mut(),
+            slabs: 0,
+            in_use: 0,
+        }
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    pub fn slab_size(&self) -> usize {
+        self.slab_size
+    }
+
+    /// Number of slabs currently owned by the cache.
+    pub fn slabs(&self) -> usize {
+        self.slabs
+    }
+
+    /// Number of objects handed out and not yet freed.
+    pub fn in_use(&self) -> usize {
+        self.in_use
+    }
+
+    pub fn alloc(&mut self, source: &mut impl BlockSource) -> Option<usize> {
+        if self.partial.is_null() {
+            self.grow(source)?;
+        }
+
+        unsafe {
+            let slab = self.partial;
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+
+            if (*slab).free.is_null() {
+                self.unlink(slab);
+            }
+
+            self.in_use += 1;
+            Some(object as usize)
+        }
+    }
+
+    /// Returns an object to its slab. Slabs that become empty are given back
+    /// to `source`.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must have been returned by [`SlabCache::alloc`] of this cache
+    /// with the same `source`, and must not be used afterwards.
+    pub unsafe fn dealloc(&mut self, addr: usize, source: &mut impl BlockSource) {
+        let slab = source.block_start(addr, self.slab_size) as *mut SlabHeader;
+        let was_full = (*slab).free.is_null();
+
+        let object = addr as *mut FreeObject;
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        self.in_use -= 1;
+
+        if (*slab).in_use == 0 {
+            if !was_full {
+                self.unlink(slab);
+            }
+            source.free_block(slab as usize, self.slab_size);
+            self.slabs -= 1;
+        } else if was_full {
+            self.push(slab);
+        }
+    }
+
+    fn grow(&mut self, source: &mut impl BlockSource) -> Option<()> {
+        let start = source.alloc_block(self.slab_size)?;
+        let first = (start + size_of::<SlabHeader>()).next_multiple_of(self.align);
+        let capacity = (start + self.slab_size - first) / self.object_size;
+
+        let mut free = ptr::null_mut();
+        for i in (0..capacity).rev() {
+            let object = (first + i * self.object_size) as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free }) };
+            free = object;
+        }
+
+        let slab = start as *mut SlabHeader;
+        unsafe {
+            slab.write(SlabHeader {
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                free,
+                in_use: 0,
+            });
+        }
+
+        self.push(slab);
+        self.slabs += 1;
+        Some(())
+    }
+
+    fn push(&mut self, slab: *mut SlabHeader) {
+        unsafe {
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = self.partial;
+            if !self.partial.is_null() {
+                (*self.partial).prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
+
+    fn unlink(&mut self, slab: *mut SlabHeader) {
+        unsafe {
+            let SlabHeader { next, prev, .. } = *slab;
+            if prev.is_null() {
+                self.partial = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+    }
+}
+
+/// General purpose small object allocator with one [`SlabCache`] per entry
+/// of [`SIZE_CLASSES`].
+#[derive(Debug)]
+pub struct SlabAllocator {
+    caches: [SlabCache; SIZE_CLASSES.len()],
+}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        let mut caches = [const { SlabCache::new(0, 1) }; SIZE_CLASSES.len()];
+
+        let mut i = 0;
+        while i < SIZE_CLASSES.len() {
+            caches[i] = SlabCache::new(SIZE_CLASSES[i], SIZE_CLASSES[i]);
+            i += 1;
+        }
+
+        Self { caches }
+    }
+
+    /// Cache of the smallest size class that fits `layout`, or `None` if the
+    /// layout is too large for slabs.
+    pub fn cache_for(&mut self, layout: Layout) -> Option<&mut SlabCache> {
+        let size = layout.size().max(layout.align());
+        self.caches
+            .iter_mut()
+            .find(|cache| cache.object_size() >= size)
+    }
+
+    pub fn caches(&self) -> impl Iterator<Item = &SlabCache> {
+        self.caches.iter()
+    }
+}
+
+impl Default for SlabAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Slab cache dedicated to objects of type `T`.
+pub struct ObjectCache<T> {
+    cache: SlabCache,
+    _marker: PhantomData<T>,
+}
+
+impl<T> ObjectCache<T> {
+    pub const fn new() -> Self {
+        Self {
+            cache: SlabCache::new(size_of::<T>(), align_of::<T>()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Allocates uninitialized memory for a `T`.
+    pub fn alloc(&mut self, source: &mut impl BlockSource) -> Option<NonNull<T>> {
+        let addr = self.cache.alloc(source)?;
+        NonNull::new(addr as *mut T)
+    }
+
+    /// Returns the memory of `object` to the cache without dropping it.
+    ///
+    /// # Safety
+    ///
+    /// `object` must have been returned by [`ObjectCache::alloc`] of this
+    /// cache with the same `source`, and must not be used afterwards.
+    pub unsafe fn dealloc(&mut self, object: NonNull<T>, source: &mut impl BlockSource) {
+        self.cache.dealloc(object.as_ptr() as usize, source);
+    }
+
+    pub fn cache(&self) -> &SlabCache {
+        &self.cache
+    }
+}
+
+impl<T> fmt::Debug for ObjectCache<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObjectCache")
+            .field("cache", &self.cache)
+            .finish()
+    }
+}
+
+impl<T> Default for ObjectCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::Heap;
+
+    use std::boxed::Box;
+
+    const HEAP_SIZE: usize = 64 * 1024;
+    const MIN_BLOCK_SIZE: usize = 64;
+
+    #[repr(align(65536))]
+    struct Memory([u8; HEAP_SIZE]);
+
+    // Every test gets its own memory, since tests run in parallel
+    fn heap() -> Heap<1> {
+        let bookkeeping = Box::leak(Box::new([0u8; 4096]));
+        let memory = Box::leak(Box::new(Memory([0; HEAP_SIZE])));
+
+        let mut heap = Heap::new(MIN_BLOCK_SIZE);
+        unsafe {
+            heap.add_region_with_bookkeeping(
+                bookkeeping.as_ptr() as usize,
+                memory.0.as_ptr() as usize,
+                HEAP_SIZE,
+            )
+            .unwrap();
+        }
+        heap
+    }
+
+    #[test]
+    fn test_slab_reuses_and_releases_pages() {
+        let mut heap = heap();
+        let mut cache = SlabCache::new(32, 32);
+
+        let a = cache.alloc(&mut heap).unwrap();
+        let b = cache.alloc(&mut heap).unwrap();
+        assert_eq!(cache.slabs(), 1);
+        assert_eq!(a % 32, 0, "Object not aligned");
+        assert_eq!(b, a + 32, "Objects not packed");
+        assert_eq!(heap.stats().allocated, PAGE_SIZE);
+
+        unsafe { cache.dealloc(a, &mut heap) };
+        assert_eq!(cache.alloc(&mut heap), Some(a), "Freed object not reused");
+
+        unsafe {
+            cache.dealloc(a, &mut heap);
+            cache.dealloc(b, &mut heap);
+        }
+        assert_eq!(cache.slabs(), 0);
+        assert_eq!(heap.stats().allocated, 0, "Empty slab not released");
+    }
+
+    #[test]
+    fn test_slab_grows_when_full() {
+        let mut heap = heap();
+        let mut cache = SlabCache::new(2048, 2048);
+
+        let objects: [usize; 8] = core::array::from_fn(|_| cache.alloc(&mut heap).unwrap());
+        // The header takes up the first object slot of each slab
+        assert_eq!(cache.slabs(), 2);
+        assert_eq!(cache.in_use(), 8);
+
+        // Freeing from a full slab makes it available again
+        unsafe { cache.dealloc(objects[0], &mut heap) };
+        assert_eq!(cache.alloc(&mut heap), Some(objects[0]));
+
+        for object in objects {
+            unsafe { cache.dealloc(object, &mut heap) };
+        }
+        assert_eq!(heap.stats().allocated, 0);
+    }
+
+    #[test]
+    fn test_size_classes() {
+        let mut slabs = SlabAllocator::new();
+
+        let size = |slabs: &mut SlabAllocator, size, align| {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            slabs.cache_for(layout).map(|cache| cache.object_size())
+        };
+
+        assert_eq!(size(&mut slabs, 1, 1), Some(8));
+        assert_eq!(size(&mut slabs, 24, 8), Some(32));
+        assert_eq!(size(&mut slabs, 8, 256), Some(256));
+        assert_eq!(size(&mut slabs, 2048, 8), Some(2048));
+        assert_eq!(size(&mut slabs, 2049, 8), None);
+    }
+
+    #[test]
+    fn test_object_cache() {
+        #[repr(align(4096))]
+        struct Table(#[allow(dead_code)] [u64; 512]);
+
+        let mut heap = heap();
+        let mut cache = ObjectCache::<Table>::new();
+
+        let table = cache.alloc(&mut heap).unwrap();
+        assert_eq!(table.as_ptr() as usize % 4096, 0);
+        assert_eq!(cache.cache().slab_size(), 32 * 1024);
+
+        unsafe { cache.dealloc(table, &mut heap) };
+        assert_eq!(cache.cache().in_use(), 0);
+    }
+}
@@ -0,0 +1,125 @@
+//! Allocation of address space identifiers.
+//!
+//! ASIDs are handed out in generations. Within a generation every address
+//! space gets its own value, so switching between them needs no flush. Once
+//! the values run out, a new generation starts with a full flush and address
+//! spaces pick up a fresh value the next time they are activated.
+
+/// ASID of an address space and the generation it was handed out in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Asid {
+    generation: u64,
+    value: u16,
+}
+
+impl Asid {
+    pub fn value(&self) -> u16 {
+        self.value
+    }
+}
+
+/// Translations that must be flushed before an address space can be used
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flush {
+    None,
+    /// Everything cached for the ASID, because it is shared with other
+    /// address spaces
+    Asid,
+    /// Everything, because a new generation has started
+    All,
+}
+
+#[derive(Debug)]
+pub struct AsidAllocator {
+    generation: u64,
+    next: u32,
+    count: u32,
+}
+
+impl AsidAllocator {
+    /// Allocator for a hart that implements `bits` ASID bits. Without any,
+    /// every address space uses ASID 0.
+    pub const fn new(bits: u32) -> Self {
+        Self {
+            generation: 1,
+            // ASID 0 is left to address spaces that were never activated
+            next: 1,
+            count: 1 << bits,
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Makes sure `asid` is valid in the current generation, handing out a
+    /// new value if it is not, and returns what has to be flushed before
+    /// the address space is activated.
+    pub fn assign(&mut self, asid: &mut Option<Asid>) -> Flush {
+        if self.count == 1 {
+            *asid = Some(Asid {
+                generation: self.generation,
+                value: 0,
+            });
+            return Flush::Asid;
+        }
+
+        if asid.is_some_and(|asid| asid.generation == self.generation) {
+            return Flush::None;
+        }
+
+        let mut flush = Flush::None;
+        if self.next == self.count {
+            self.generation += 1;
+            self.next = 1;
+            flush = Flush::All;
+        }
+
+        *asid = Some(Asid {
+            generation: self.generation,
+            value: self.next as u16,
+        });
+        self.next += 1;
+
+        flush
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asid_generations() {
+        let mut asids = AsidAllocator::new(2);
+        let mut spaces = [None; 4];
+
+        for (i, asid) in spaces[..3].iter_mut().enumerate() {
+            assert_eq!(asids.assign(asid), Flush::None);
+            assert_eq!(asid.unwrap().value(), i as u16 + 1);
+        }
+
+        // Switching back needs no new value and no flush
+        assert_eq!(asids.assign(&mut spaces[0]), Flush::None);
+        assert_eq!(spaces[0].unwrap().value(), 1);
+
+        // The fourth address space starts a new generation
+        assert_eq!(asids.assign(&mut spaces[3]), Flush::All);
+        assert_eq!(spaces[3].unwrap().value(), 1);
+        assert_eq!(asids.generation(), 2);
+
+        // Address spaces of the old generation get a fresh value
+        assert_eq!(asids.assign(&mut spaces[0]), Flush::None);
+        assert_eq!(spaces[0].unwrap().value(), 2);
+    }
+
+    #[test]
+    fn test_without_asids() {
+        let mut asids = AsidAllocator::new(0);
+        let mut asid = None;
+
+        assert_eq!(asids.assign(&mut asid), Flush::Asid);
+        assert_eq!(asids.assign(&mut asid), Flush::Asid);
+        assert_eq!(asid.unwrap().value(), 0);
+    }
+}
@@ -1,3 +1,5 @@
 #![cfg_attr(not(test), no_std)]
 
+pub mod asid;
+pub mod mmu;
 pub mod page;
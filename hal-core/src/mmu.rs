@@ -0,0 +1,1186 @@
+//! Page table operations on top of an abstract physical memory.
+//!
+//! Page tables refer to each other by physical address. All accesses to them
+//! go through [`PhysMemory`], so the same code runs on the hart, where
+//! physical memory is identity mapped, and in host tests, where it is
+//! simulated.
+
+use crate::page::{
+    level_size, EntryFlags, Frame, FrameAllocator, Mappings, Paddr, Page, PageTable,
+    PageTableEntry, PagingMode, Vaddr, MAX_LEVELS, PAGE_SIZE,
+};
+
+/// Software bit of leaves whose frames were allocated for the hierarchy and
+/// are released with it. Everything else, e.g. device memory, is only
+/// borrowed.
+pub const OWNED: EntryFlags = EntryFlags::from_bits_truncate(1 << 8);
+
+/// Software bit of owned leaves whose frame is shared with other hierarchies.
+/// They are mapped read-only and copied on the first write that their owner
+/// allows.
+pub const COW: EntryFlags = EntryFlags::from_bits_truncate(1 << 9);
+
+/// Access to physical memory
+pub trait PhysMemory {
+    /// Pointer through which the byte at `paddr` can be accessed
+    fn ptr(&self, paddr: Paddr) -> *mut u8;
+
+    /// Pointer to the page table at `paddr`, which must be page aligned
+    fn table(&self, paddr: Paddr) -> *mut PageTable {
+        self.ptr(paddr).cast()
+    }
+}
+
+/// Physical memory that can be accessed at its own address
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IdentityMemory;
+
+impl PhysMemory for IdentityMemory {
+    fn ptr(&self, paddr: Paddr) -> *mut u8 {
+        paddr.as_mut_ptr()
+    }
+}
+
+/// Cache of translations that has to be told about changed entries
+pub trait Tlb {
+    /// Drops cached translations of the leaf entry that maps `vaddr`.
+    fn flush_page(&mut self, vaddr: Vaddr);
+
+    /// Drops all cached translations, including those of non-leaf entries.
+    fn flush_all(&mut self);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// No frame was left for an intermediate page table or the mapped page
+    OutOfFrames,
+    /// The page is already mapped to a different frame
+    AlreadyMapped,
+    /// The page is not mapped
+    NotMapped,
+    /// The area overlaps an existing area
+    Overlap,
+    /// No free range of virtual addresses is large enough
+    NoSpace,
+}
+
+/// Kind of a memory access
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    /// Permission the access needs
+    pub fn flag(self) -> EntryFlags {
+        match self {
+            Access::Read => EntryFlags::READ,
+            Access::Write => EntryFlags::WRITE,
+            Access::Execute => EntryFlags::EXECUTE,
+        }
+    }
+}
+
+/// Privilege mode of an access and the mstatus bits that affect how it is
+/// checked
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Privilege {
+    /// U-mode instead of S-mode
+    pub user: bool,
+    /// SUM: S-mode may read and write user pages
+    pub sum: bool,
+    /// MXR: pages that are only executable may be read
+    pub mxr: bool,
+}
+
+/// Reason for a page fault raised by [`PageTables::translate_access`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageFault {
+    /// The upper bits of the address are not a copy of its highest
+    /// significant bit
+    NonCanonical,
+    /// An entry on the way is not valid, or a non-leaf entry was found at
+    /// level 0
+    Invalid,
+    /// An entry has reserved bits set or is writable but not readable
+    Reserved,
+    /// The leaf does not permit the access in the privilege mode
+    Denied,
+    /// A superpage with a PPN that is not aligned to its size
+    MisalignedSuperpage,
+    /// The leaf is not marked accessed, which the hart does not do itself
+    NotAccessed,
+    /// A write to a leaf that is not marked dirty
+    NotDirty,
+}
+
+// Entries on the way from the root to the leaf that maps an address,
+// indexed by level.
+struct Walk {
+    entries: [*mut PageTableEntry; MAX_LEVELS],
+    level: usize,
+    mapped: bool,
+}
+
+impl Walk {
+    fn leaf(&mut self) -> &mut PageTableEntry {
+        unsafe { &mut *self.entries[self.level] }
+    }
+}
+
+/// Page table hierarchy below a root table in physical memory `M`, which
+/// also provides the frames for new tables and the TLB.
+pub struct PageTables<'a, M> {
+    mem: &'a mut M,
+    mode: PagingMode,
+    root: Paddr,
+}
+
+impl<'a, M: PhysMemory + FrameAllocator + Tlb> PageTables<'a, M> {
+    pub fn new(mem: &'a mut M, mode: PagingMode, root: Paddr) -> Self {
+        Self { mem, mode, root }
+    }
+
+    pub fn mode(&self) -> PagingMode {
+        self.mode
+    }
+
+    pub fn root(&self) -> Paddr {
+        self.root
+    }
+
+    /// Every mapping of the hierarchy, see [`PageTable::mappings`].
+    pub fn mappings(&self) -> Mappings<'_, M> {
+        Mappings::new(&*self.mem, self.mode, self.root)
+    }
+
+    // Maps `vaddr` to `paddr` with a leaf entry at `level`, both of which
+    // must be aligned to the size of that level. Returns `false` if the slot
+    // is already taken by a page table, in which case the caller has to use
+    // smaller pages.
+    fn map_leaf(
+        &mut self,
+        vaddr: Vaddr,
+        paddr: Paddr,
+        level: usize,
+        flags: EntryFlags,
+    ) -> Result<bool, MapError> {
+        let vpn = vaddr.indexed_vpn();
+        let mut table = unsafe { &mut *self.mem.table(self.root) };
+
+        for lv in (level..self.mode.levels()).rev() {
+            let index = vpn[lv];
+            let entry = table.entry_mut(index);
+
+            if entry.is_valid() {
+                if entry.is_leaf() {
+                    // Mapping the same frame again is fine, changing the
+                    // frame or flags of a mapping is up to `remap` and
+                    // `protect`
+                    let offset = vaddr.inner() & (level_size(lv) - 1);
+                    if entry.paddr().inner() + offset != paddr.inner() {
+                        return Err(MapError::AlreadyMapped);
+                    }
+                    return Ok(true);
+                }
+
+                if lv == level {
+                    return Ok(false);
+                }
+
+                table = unsafe { &mut *self.mem.table(entry.paddr()) };
+            } else {
+                if lv == level {
+                    // Create a leaf entry and return
+                    *entry = leaf_entry(paddr, flags);
+                    return Ok(true);
+                }
+
+                // Frames are zeroed, so the new table has no valid entries
+                let next_page_table_paddr = self
+                    .mem
+                    .allocate_frame()
+                    .ok_or(MapError::OutOfFrames)?
+                    .addr();
+
+                *entry = PageTableEntry::new(EntryFlags::VALID);
+                entry.set_paddr(next_page_table_paddr);
+                table = unsafe { &mut *self.mem.table(next_page_table_paddr) };
+            }
+        }
+
+        unreachable!("Leaf level {} out of range", level)
+    }
+
+    /// Maps `page` to `frame`, which may already be mapped there.
+    pub fn map(&mut self, page: Page, frame: Frame, flags: EntryFlags) -> Result<(), MapError> {
+        self.map_leaf(page.addr(), frame.addr(), 0, flags)
+            .map(|_| ())
+    }
+
+    /// Maps `size` bytes at `vstart` to `pstart`, using the largest pages
+    /// that the alignment of both addresses and the remaining length allow.
+    /// All three must be page aligned. Pages mapped before a failure stay
+    /// mapped.
+    pub fn map_region(
+        &mut self,
+        vstart: u64,
+        pstart: u64,
+        size: u64,
+        flags: EntryFlags,
+    ) -> Result<(), MapError> {
+        let mut offset = 0;
+
+        while offset < size {
+            let vaddr = vstart + offset;
+            let paddr = pstart + offset;
+
+            let mut level = (0..self.mode.levels())
+                .rev()
+                .find(|&level| {
+                    let page_size = level_size(level);
+                    vaddr.is_multiple_of(page_size)
+                        && paddr.is_multiple_of(page_size)
+                        && size - offset >= page_size
+                })
+                .unwrap_or(0);
+
+            while !self.map_leaf(Vaddr::new(vaddr), Paddr::new(paddr), level, flags)? {
+                level -= 1;
+            }
+
+            offset += level_size(level);
+        }
+
+        Ok(())
+    }
+
+    // Replaces the superpage `entry` at `level` by a table of leaves one
+    // level below that map the same memory with the same flags.
+    fn split(&mut self, entry: &mut PageTableEntry, level: usize) -> Result<(), MapError> {
+        let table_paddr = self
+            .mem
+            .allocate_frame()
+            .ok_or(MapError::OutOfFrames)?
+            .addr();
+        let table = unsafe { &mut *self.mem.table(table_paddr) };
+
+        let flags = entry.flags();
+        let base = entry.paddr().inner();
+        for i in 0..512 {
+            let paddr = Paddr::new(base + i as u64 * level_size(level - 1));
+            let mut leaf = PageTableEntry::new(flags);
+            leaf.set_paddr(paddr);
+            *table.entry_mut(i) = leaf;
+        }
+
+        *entry = PageTableEntry::new(EntryFlags::VALID);
+        entry.set_paddr(table_paddr);
+        Ok(())
+    }
+
+    // Walks down to the entry that maps `vaddr`, splitting superpages larger
+    // than `max_level` on the way. The walk ends early at an invalid entry.
+    fn walk(&mut self, vaddr: Vaddr, max_level: usize) -> Result<Walk, MapError> {
+        let vpn = vaddr.indexed_vpn();
+        let mut table = unsafe { &mut *self.mem.table(self.root) };
+        let mut walk = Walk {
+            entries: [core::ptr::null_mut(); MAX_LEVELS],
+            level: 0,
+            mapped: false,
+        };
+
+        for lv in (0..self.mode.levels()).rev() {
+            let entry = table.entry_mut(vpn[lv]);
+            walk.entries[lv] = entry;
+            walk.level = lv;
+
+            if !entry.is_valid() {
+                return Ok(walk);
+            }
+
+            if entry.is_leaf() {
+                if lv <= max_level {
+                    walk.mapped = true;
+                    return Ok(walk);
+                }
+                self.split(entry, lv)?;
+            }
+
+            table = unsafe { &mut *self.mem.table(entry.paddr()) };
+        }
+
+        unreachable!("Page table walk did not end at a leaf")
+    }
+
+    // Clears the leaf of `walk` and frees the tables that became empty, apart
+    // from the root. The frames of the cleared leaf are passed to `unmapped`.
+    fn clear_leaf(&mut self, walk: &mut Walk, vaddr: Vaddr, unmapped: &mut impl FnMut(Frame)) {
+        let size = level_size(walk.level);
+        let leaf = walk.leaf();
+        let base = leaf.paddr().inner();
+        for offset in (0..size).step_by(PAGE_SIZE as usize) {
+            unmapped(Frame::containing_address(base + offset));
+        }
+        *leaf = PageTableEntry::new(EntryFlags::empty());
+        self.mem.flush_page(vaddr);
+
+        let mut freed = false;
+        for lv in walk.level + 1..self.mode.levels() {
+            let entry = unsafe { &mut *walk.entries[lv] };
+            let table = entry.paddr();
+            if !unsafe { &*self.mem.table(table) }.is_empty() {
+                break;
+            }
+
+            *entry = PageTableEntry::new(EntryFlags::empty());
+            self.mem
+                .release_frame(Frame::containing_address(table.inner()));
+            freed = true;
+        }
+
+        // Page specific flushes only cover leaf entries
+        if freed {
+            self.mem.flush_all();
+        }
+    }
+
+    // Largest level whose pages fit into `vaddr..end` at `vaddr`
+    fn fitting_level(&self, vaddr: u64, end: u64) -> usize {
+        (0..self.mode.levels())
+            .rev()
+            .find(|&level| {
+                vaddr.is_multiple_of(level_size(level)) && end - vaddr >= level_size(level)
+            })
+            .unwrap_or(0)
+    }
+
+    /// Removes the mapping of `page` and returns the frame it was mapped to.
+    /// Superpages around the page are split, which needs a frame for the new
+    /// table.
+    pub fn unmap(&mut self, page: Page) -> Result<Option<Frame>, MapError> {
+        let mut walk = self.walk(page.addr(), 0)?;
+        if !walk.mapped {
+            return Ok(None);
+        }
+
+        let mut frame = None;
+        self.clear_leaf(&mut walk, page.addr(), &mut |unmapped| {
+            frame = Some(unmapped)
+        });
+        Ok(frame)
+    }
+
+    /// Removes every mapping in `start..end` and passes the frames that were
+    /// mapped there to `unmapped`. Both addresses must be page aligned.
+    pub fn unmap_range(
+        &mut self,
+        start: u64,
+        end: u64,
+        mut unmapped: impl FnMut(Frame),
+    ) -> Result<(), MapError> {
+        let mut vaddr = start;
+        while vaddr < end {
+            let level = self.fitting_level(vaddr, end);
+            let mut walk = self.walk(Vaddr::new(vaddr), level)?;
+            if walk.mapped {
+                self.clear_leaf(&mut walk, Vaddr::new(vaddr), &mut unmapped);
+            }
+
+            // Skip everything covered by the entry, which may be an invalid
+            // entry high up in the tree
+            vaddr = (vaddr + 1).next_multiple_of(level_size(walk.level));
+        }
+
+        Ok(())
+    }
+
+    /// Changes the flags of every page in `start..end`, keeping the software
+    /// bits of the entries. Both addresses must be page aligned. Fails if a
+    /// page in the range is not mapped, in which case the pages before it
+    /// have already been changed.
+    pub fn protect(&mut self, start: u64, end: u64, flags: EntryFlags) -> Result<(), MapError> {
+        let mut vaddr = start;
+        while vaddr < end {
+            let level = self.fitting_level(vaddr, end);
+            let mut walk = self.walk(Vaddr::new(vaddr), level)?;
+            if !walk.mapped {
+                return Err(MapError::NotMapped);
+            }
+
+            let leaf = walk.leaf();
+            let rsw = leaf.flags() & EntryFlags::RSW;
+            *leaf = leaf_entry(leaf.paddr(), flags | rsw);
+            self.mem.flush_page(Vaddr::new(vaddr));
+
+            vaddr += level_size(walk.level);
+        }
+
+        Ok(())
+    }
+
+    /// Like [`PageTables::protect`], but skips pages that are not mapped and
+    /// keeps owned frames that are shared with other hierarchies
+    /// copy-on-write. Those stay read-only and are marked `COW` instead.
+    pub fn protect_cow(&mut self, start: u64, end: u64, flags: EntryFlags) -> Result<(), MapError> {
+        let mut vaddr = start;
+        while vaddr < end {
+            let level = self.fitting_level(vaddr, end);
+            let mut walk = self.walk(Vaddr::new(vaddr), level)?;
+            if walk.mapped {
+                let leaf = walk.leaf();
+                let rsw = leaf.flags() & EntryFlags::RSW;
+                let frame = Frame::containing_address(leaf.paddr().inner());
+                let shared =
+                    rsw.contains(OWNED) && (rsw.contains(COW) || self.mem.refcount(frame) > 1);
+
+                let flags = if shared {
+                    (flags - EntryFlags::WRITE) | rsw | COW
+                } else {
+                    flags | rsw
+                };
+                *leaf = leaf_entry(leaf.paddr(), flags);
+                self.mem.flush_page(Vaddr::new(vaddr));
+            }
+
+            vaddr = (vaddr + 1).next_multiple_of(level_size(walk.level));
+        }
+
+        Ok(())
+    }
+
+    /// Maps everything in `start..end` into the hierarchy below `child` as
+    /// well, which must not have mappings there yet. Owned frames gain a
+    /// reference, and writable ones become `COW` in both hierarchies.
+    pub fn share_cow(&mut self, child: Paddr, start: u64, end: u64) -> Result<(), MapError> {
+        let mut vaddr = start;
+        while vaddr < end {
+            let level = self.fitting_level(vaddr, end);
+            let mut walk = self.walk(Vaddr::new(vaddr), level)?;
+            if walk.mapped {
+                let leaf = walk.leaf();
+                let mut flags = leaf.flags();
+                if flags.contains(OWNED) && flags.contains(EntryFlags::WRITE) {
+                    flags = (flags - EntryFlags::WRITE) | COW;
+                    *leaf = leaf_entry(leaf.paddr(), flags);
+                    self.mem.flush_page(Vaddr::new(vaddr));
+                }
+
+                let paddr = leaf.paddr();
+                let mut child = PageTables::new(&mut *self.mem, self.mode, child);
+                if !child.map_leaf(Vaddr::new(vaddr), paddr, walk.level, flags)? {
+                    return Err(MapError::AlreadyMapped);
+                }
+
+                if flags.contains(OWNED) {
+                    for offset in (0..level_size(walk.level)).step_by(PAGE_SIZE as usize) {
+                        let frame = Frame::containing_address(paddr.inner() + offset);
+                        self.mem.retain_frame(frame);
+                    }
+                }
+            }
+
+            vaddr = (vaddr + 1).next_multiple_of(level_size(walk.level));
+        }
+
+        Ok(())
+    }
+
+    /// Makes the `COW` page `page` writable. It gets a copy of its frame,
+    /// unless no other hierarchy shares that anymore. A superpage around it
+    /// is split first, so that only `page` is copied.
+    pub fn break_cow(&mut self, page: Page) -> Result<(), MapError> {
+        let mut walk = self.walk(page.addr(), 0)?;
+        if !walk.mapped {
+            return Err(MapError::NotMapped);
+        }
+        let entry = *walk.leaf();
+        let shared = Frame::containing_address(entry.paddr().inner());
+        let flags = (entry.flags() - COW) | EntryFlags::WRITE;
+
+        if self.mem.refcount(shared) == 1 {
+            self.remap(page, shared, flags)?;
+            return Ok(());
+        }
+
+        let frame = self.mem.allocate_frame().ok_or(MapError::OutOfFrames)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.mem.ptr(shared.addr()),
+                self.mem.ptr(frame.addr()),
+                PAGE_SIZE as usize,
+            );
+        }
+
+        if let Err(err) = self.remap(page, frame, flags) {
+            self.mem.release_frame(frame);
+            return Err(err);
+        }
+        self.mem.release_frame(shared);
+
+        Ok(())
+    }
+
+    /// Maps `page` to `frame`, replacing any existing mapping, and returns
+    /// the frame that was mapped before.
+    pub fn remap(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: EntryFlags,
+    ) -> Result<Option<Frame>, MapError> {
+        let mut walk = self.walk(page.addr(), 0)?;
+        if !walk.mapped {
+            self.map(page, frame, flags)?;
+            return Ok(None);
+        }
+
+        let leaf = walk.leaf();
+        let old = Frame::containing_address(leaf.paddr().inner());
+        *leaf = leaf_entry(frame.addr(), flags);
+        self.mem.flush_page(page.addr());
+
+        Ok(Some(old))
+    }
+
+    /// Leaf entry that maps `vaddr` and its level, if any.
+    pub fn lookup(&self, vaddr: Vaddr) -> Option<(PageTableEntry, usize)> {
+        let vpn = vaddr.indexed_vpn();
+        let mut table = unsafe { &*self.mem.table(self.root) };
+
+        for lv in (0..self.mode.levels()).rev() {
+            let entry = table.entry(vpn[lv]);
+            if !entry.is_valid() {
+                return None;
+            }
+
+            if entry.is_leaf() {
+                return Some((*entry, lv));
+            }
+
+            table = unsafe { &*self.mem.table(entry.paddr()) };
+        }
+
+        None
+    }
+
+    /// Physical address that `vaddr` is mapped to, regardless of the flags.
+    pub fn translate(&self, vaddr: Vaddr) -> Option<Paddr> {
+        let (entry, lv) = self.lookup(vaddr)?;
+
+        // Superpages keep the lower bits of the address as offset
+        let offset = vaddr.inner() & (level_size(lv) - 1);
+        Some(Paddr::new(entry.paddr().inner() | offset))
+    }
+
+    /// Translates `vaddr` like the hart does for an `access` with
+    /// `privilege`, including the checks that raise page faults. Accessed and
+    /// dirty bits are never set, a clear one faults instead.
+    pub fn translate_access(
+        &self,
+        vaddr: Vaddr,
+        access: Access,
+        privilege: Privilege,
+    ) -> Result<Paddr, PageFault> {
+        if !self.mode.is_canonical(vaddr) {
+            return Err(PageFault::NonCanonical);
+        }
+
+        let vpn = vaddr.indexed_vpn();
+        let mut table = unsafe { &*self.mem.table(self.root) };
+
+        for lv in (0..self.mode.levels()).rev() {
+            let entry = table.entry(vpn[lv]);
+            let flags = entry.flags();
+
+            if !entry.is_valid() {
+                return Err(PageFault::Invalid);
+            }
+            // Bits above the PPN are reserved or belong to extensions that
+            // are not supported
+            if entry.bits() >> 54 != 0
+                || (flags.contains(EntryFlags::WRITE) && !flags.contains(EntryFlags::READ))
+            {
+                return Err(PageFault::Reserved);
+            }
+
+            if !entry.is_leaf() {
+                table = unsafe { &*self.mem.table(entry.paddr()) };
+                continue;
+            }
+
+            check_permissions(flags, access, privilege)?;
+
+            let offset = vaddr.inner() & (level_size(lv) - 1);
+            if entry.paddr().inner() & (level_size(lv) - 1) != 0 {
+                return Err(PageFault::MisalignedSuperpage);
+            }
+            if !flags.contains(EntryFlags::ACCESSED) {
+                return Err(PageFault::NotAccessed);
+            }
+            if access == Access::Write && !flags.contains(EntryFlags::DIRTY) {
+                return Err(PageFault::NotDirty);
+            }
+
+            return Ok(Paddr::new(entry.paddr().inner() | offset));
+        }
+
+        Err(PageFault::Invalid)
+    }
+}
+
+// Leaf entry of a fresh mapping
+fn leaf_entry(paddr: Paddr, flags: EntryFlags) -> PageTableEntry {
+    let mut entry =
+        PageTableEntry::new(EntryFlags::VALID | EntryFlags::ACCESSED | EntryFlags::DIRTY | flags);
+    entry.set_paddr(paddr);
+    entry
+}
+
+fn check_permissions(
+    flags: EntryFlags,
+    access: Access,
+    privilege: Privilege,
+) -> Result<(), PageFault> {
+    let user_page = flags.contains(EntryFlags::USER);
+    // S-mode never executes user pages, even with SUM
+    let mode_allowed = if privilege.user {
+        user_page
+    } else {
+        !user_page || (privilege.sum && access != Access::Execute)
+    };
+
+    let access_allowed = flags.contains(access.flag())
+        || (access == Access::Read && privilege.mxr && flags.contains(EntryFlags::EXECUTE));
+
+    if mode_allowed && access_allowed {
+        Ok(())
+    } else {
+        Err(PageFault::Denied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::cell::UnsafeCell;
+    use std::{boxed::Box, vec, vec::Vec};
+
+    /// Physical address of the first simulated frame
+    const BASE: u64 = 0x8000_0000;
+
+    #[repr(align(4096))]
+    struct SimFrame([u8; PAGE_SIZE as usize]);
+
+    /// Physical memory of a few frames at `BASE`, backed by host memory
+    struct SimMemory {
+        frames: Vec<Box<UnsafeCell<SimFrame>>>,
+        refcounts: Vec<u16>,
+        flushed_pages: Vec<u64>,
+        full_flushes: usize,
+    }
+
+    impl SimMemory {
+        fn new(frames: usize) -> Self {
+            Self {
+                frames: (0..frames)
+                    .map(|_| Box::new(UnsafeCell::new(SimFrame([0; PAGE_SIZE as usize]))))
+                    .collect(),
+                refcounts: vec![0; frames],
+                flushed_pages: Vec::new(),
+                full_flushes: 0,
+            }
+        }
+
+        fn root(&mut self) -> Paddr {
+            self.allocate_frame().expect("No frame for the root").addr()
+        }
+
+        fn allocated(&self) -> usize {
+            self.refcounts.iter().filter(|&&count| count > 0).count()
+        }
+
+        fn index(&self, paddr: Paddr) -> usize {
+            let index = (paddr.inner().wrapping_sub(BASE) / PAGE_SIZE) as usize;
+            assert!(
+                index < self.frames.len(),
+                "0x{:x} is outside of simulated memory",
+                paddr.inner()
+            );
+            index
+        }
+    }
+
+    impl PhysMemory for SimMemory {
+        fn ptr(&self, paddr: Paddr) -> *mut u8 {
+            let frame = self.frames[self.index(paddr)].get().cast::<u8>();
+            unsafe { frame.add((paddr.inner() % PAGE_SIZE) as usize) }
+        }
+    }
+
+    impl FrameAllocator for SimMemory {
+        fn allocate_frame(&mut self) -> Option<Frame> {
+            let index = self.refcounts.iter().position(|&count| count == 0)?;
+            self.frames[index].get_mut().0.fill(0);
+            self.refcounts[index] = 1;
+            Some(Frame::containing_address(BASE + index as u64 * PAGE_SIZE))
+        }
+
+        fn retain_frame(&mut self, frame: Frame) {
+            let index = self.index(frame.addr());
+            self.refcounts[index] += 1;
+        }
+
+        fn release_frame(&mut self, frame: Frame) -> bool {
+            let index = self.index(frame.addr());
+            self.refcounts[index] -= 1;
+            self.refcounts[index] == 0
+        }
+
+        fn refcount(&self, frame: Frame) -> u16 {
+            self.refcounts[self.index(frame.addr())]
+        }
+    }
+
+    impl Tlb for SimMemory {
+        fn flush_page(&mut self, vaddr: Vaddr) {
+            self.flushed_pages.push(vaddr.inner());
+        }
+
+        fn flush_all(&mut self) {
+            self.full_flushes += 1;
+        }
+    }
+
+    fn page(addr: u64) -> Page {
+        Page::containing_address(addr)
+    }
+
+    fn frame(addr: u64) -> Frame {
+        Frame::containing_address(addr)
+    }
+
+    fn translate(tables: &PageTables<SimMemory>, vaddr: u64) -> Option<u64> {
+        tables
+            .translate(Vaddr::new(vaddr))
+            .map(|paddr| paddr.inner())
+    }
+
+    fn level(tables: &PageTables<SimMemory>, vaddr: u64) -> Option<usize> {
+        tables.lookup(Vaddr::new(vaddr)).map(|(_, level)| level)
+    }
+
+    #[test]
+    fn test_map_and_translate() {
+        let mut mem = SimMemory::new(8);
+        let root = mem.root();
+        let mut tables = PageTables::new(&mut mem, PagingMode::Sv39, root);
+
+        tables
+            .map(page(0x1000), frame(0x1_0000_0000), EntryFlags::RW)
+            .unwrap();
+        assert_eq!(translate(&tables, 0x1234), Some(0x1_0000_0234));
+        assert_eq!(level(&tables, 0x1000), Some(0));
+        assert_eq!(translate(&tables, 0x2000), None);
+        assert_eq!(translate(&tables, 0x40_0000), None);
+
+        // Mapping the same frame again is fine, another one is not
+        tables
+            .map(page(0x1000), frame(0x1_0000_0000), EntryFlags::RW)
+            .unwrap();
+        assert_eq!(
+            tables.map(page(0x1000), frame(0x1_0000_1000), EntryFlags::RW),
+            Err(MapError::AlreadyMapped)
+        );
+
+        // A neighbouring page shares the tables
+        tables
+            .map(page(0x2000), frame(0x1_0000_1000), EntryFlags::RW)
+            .unwrap();
+        let mappings: Vec<_> = tables.mappings().collect();
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].size, 0x2000);
+
+        // The root and one table per level below it
+        assert_eq!(mem.allocated(), 3);
+    }
+
+    #[test]
+    fn test_out_of_frames() {
+        let mut mem = SimMemory::new(2);
+        let root = mem.root();
+        let mut tables = PageTables::new(&mut mem, PagingMode::Sv39, root);
+
+        assert_eq!(
+            tables.map(page(0x1000), frame(0x1_0000_0000), EntryFlags::RW),
+            Err(MapError::OutOfFrames)
+        );
+    }
+
+    #[test]
+    fn test_superpages() {
+        let mut mem = SimMemory::new(8);
+        let root = mem.root();
+        let mut tables = PageTables::new(&mut mem, PagingMode::Sv39, root);
+
+        // A page, a megapage and a page
+        tables
+            .map_region(0x1f_f000, 0x801f_f000, 0x20_2000, EntryFlags::RW)
+            .unwrap();
+        assert_eq!(level(&tables, 0x1f_f000), Some(0));
+        assert_eq!(level(&tables, 0x20_0000), Some(1));
+        assert_eq!(level(&tables, 0x40_0000), Some(0));
+        assert_eq!(translate(&tables, 0x3f_fabc), Some(0x803f_fabc));
+        assert_eq!(translate(&tables, 0x40_1000), None);
+
+        // A gigapage
+        tables
+            .map_region(0x4000_0000, 0xc000_0000, 0x4000_0000, EntryFlags::RX)
+            .unwrap();
+        assert_eq!(level(&tables, 0x7fff_ffff), Some(2));
+        assert_eq!(translate(&tables, 0x7fff_ffff), Some(0xffff_ffff));
+
+        // Where a table already exists, smaller pages are used
+        tables
+            .map(page(0x60_0000), frame(0x8060_0000), EntryFlags::RW)
+            .unwrap();
+        tables
+            .map_region(0x60_0000, 0x8060_0000, 0x20_0000, EntryFlags::RW)
+            .unwrap();
+        assert_eq!(level(&tables, 0x7f_f000), Some(0));
+        assert_eq!(translate(&tables, 0x7f_f000), Some(0x807f_f000));
+
+        let levels: Vec<_> = tables
+            .mappings()
+            .map(|mapping| (mapping.vstart, mapping.level))
+            .collect();
+        assert_eq!(
+            levels,
+            [
+                (0x1f_f000, 0),
+                (0x20_0000, 1),
+                (0x40_0000, 0),
+                (0x60_0000, 0),
+                (0x4000_0000, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unmap_splits_and_frees() {
+        let mut mem = SimMemory::new(8);
+        let root = mem.root();
+        let mut tables = PageTables::new(&mut mem, PagingMode::Sv39, root);
+
+        tables
+            .map_region(0x20_0000, 0x8020_0000, 0x20_0000, EntryFlags::RW)
+            .unwrap();
+        assert!(tables.unmap(page(0x50_0000)).unwrap().is_none());
+
+        // The megapage is split around the unmapped page
+        let unmapped = tables.unmap(page(0x20_1000)).unwrap();
+        assert_eq!(
+            unmapped.map(|frame| frame.addr().inner()),
+            Some(0x8020_1000)
+        );
+        assert_eq!(translate(&tables, 0x20_1000), None);
+        assert_eq!(translate(&tables, 0x20_2000), Some(0x8020_2000));
+        assert_eq!(level(&tables, 0x20_0000), Some(0));
+
+        let mut frames = Vec::new();
+        tables
+            .unmap_range(0x20_0000, 0x40_0000, |frame| frames.push(frame))
+            .unwrap();
+        assert_eq!(frames.len(), 511);
+        assert!(tables.mappings().next().is_none());
+
+        // Only the root is left
+        assert_eq!(mem.allocated(), 1);
+        assert!(mem.flushed_pages.contains(&0x20_1000));
+        assert!(mem.full_flushes > 0);
+    }
+
+    #[test]
+    fn test_protect_and_remap() {
+        let mut mem = SimMemory::new(8);
+        let root = mem.root();
+        let mut tables = PageTables::new(&mut mem, PagingMode::Sv39, root);
+
+        tables
+            .map_region(0x1000, 0x8000_1000, 0x2000, EntryFlags::RW.with_rsw(1))
+            .unwrap();
+        tables.protect(0x1000, 0x3000, EntryFlags::READ).unwrap();
+
+        let (entry, _) = tables.lookup(Vaddr::new(0x2000)).unwrap();
+        assert!(!entry.flags().contains(EntryFlags::WRITE));
+        assert_eq!(entry.rsw(), 1);
+        assert_eq!(
+            tables.protect(0x1000, 0x4000, EntryFlags::READ),
+            Err(MapError::NotMapped)
+        );
+
+        let old = tables
+            .remap(page(0x1000), frame(0x9000_0000), EntryFlags::RW)
+            .unwrap();
+        assert_eq!(old.map(|frame| frame.addr().inner()), Some(0x8000_1000));
+        assert_eq!(translate(&tables, 0x1008), Some(0x9000_0008));
+        assert_eq!(
+            tables
+                .remap(page(0x5000), frame(0x9000_1000), EntryFlags::RW)
+                .unwrap()
+                .map(|frame| frame.addr().inner()),
+            None
+        );
+        // The failed protect changed the pages before the gap
+        assert_eq!(mem.flushed_pages, [0x1000, 0x2000, 0x1000, 0x2000, 0x1000]);
+    }
+
+    #[test]
+    fn test_share_cow_protect_and_write() {
+        let mode = PagingMode::Sv39;
+        let mut mem = SimMemory::new(16);
+        let parent = mem.root();
+        let child = mem.root();
+        let data = mem.allocate_frame().unwrap();
+        let stack = mem.allocate_frame().unwrap();
+        unsafe { *mem.ptr(data.addr()) = 1 };
+
+        let mut tables = PageTables::new(&mut mem, mode, parent);
+        let read_only = EntryFlags::READ | EntryFlags::USER | OWNED;
+        tables.map(page(0x1000), data, read_only).unwrap();
+        tables
+            .map(page(0x2000), stack, EntryFlags::RWU | OWNED)
+            .unwrap();
+        tables.share_cow(child, 0, mode.user_end()).unwrap();
+
+        // Only the writable page becomes copy-on-write
+        for root in [parent, child] {
+            let tables = PageTables::new(&mut mem, mode, root);
+            let (entry, _) = tables.lookup(Vaddr::new(0x1000)).unwrap();
+            assert_eq!(entry.flags() & EntryFlags::RSW, OWNED);
+            let (entry, _) = tables.lookup(Vaddr::new(0x2000)).unwrap();
+            assert_eq!(entry.flags() & EntryFlags::RSW, OWNED | COW);
+            assert!(!entry.flags().contains(EntryFlags::WRITE));
+        }
+        assert_eq!(mem.refcount(data), 2);
+        assert_eq!(mem.refcount(stack), 2);
+
+        // Making the shared read-only page writable keeps it shared
+        let mut tables = PageTables::new(&mut mem, mode, parent);
+        tables.protect_cow(0, 0x2000, EntryFlags::RWU).unwrap();
+        let (entry, _) = tables.lookup(Vaddr::new(0x1000)).unwrap();
+        assert!(!entry.flags().contains(EntryFlags::WRITE));
+        assert!(entry.flags().contains(OWNED | COW));
+
+        // The write gets a copy, the child keeps the original
+        tables.break_cow(page(0x1000)).unwrap();
+        let copy = tables.translate(Vaddr::new(0x1000)).unwrap();
+        let (entry, _) = tables.lookup(Vaddr::new(0x1000)).unwrap();
+        assert!(entry.flags().contains(EntryFlags::WRITE));
+        assert_ne!(copy.inner(), data.addr().inner());
+        unsafe {
+            assert_eq!(*mem.ptr(copy), 1);
+            *mem.ptr(copy) = 2;
+            assert_eq!(*mem.ptr(data.addr()), 1);
+        }
+        assert_eq!(mem.refcount(data), 1);
+
+        // The last hierarchy sharing the frame may write it in place
+        let mut tables = PageTables::new(&mut mem, mode, child);
+        tables.protect_cow(0x1000, 0x2000, EntryFlags::RWU).unwrap();
+        let (entry, _) = tables.lookup(Vaddr::new(0x1000)).unwrap();
+        assert!(entry.flags().contains(EntryFlags::WRITE));
+        assert_eq!(translate(&tables, 0x1000), Some(data.addr().inner()));
+    }
+
+    #[test]
+    fn test_break_cow_splits_superpages() {
+        let mode = PagingMode::Sv39;
+        let mut mem = SimMemory::new(520);
+        // The frames of a megapage, which come first and are aligned
+        for _ in 0..512 {
+            mem.allocate_frame().unwrap();
+        }
+        let parent = mem.root();
+        let child = mem.root();
+        unsafe { *mem.ptr(Paddr::new(BASE + 0x1000)) = 1 };
+
+        let mut tables = PageTables::new(&mut mem, mode, parent);
+        tables
+            .map_region(0x20_0000, BASE, 0x20_0000, EntryFlags::RWU | OWNED)
+            .unwrap();
+        tables.share_cow(child, 0, mode.user_end()).unwrap();
+        assert_eq!(level(&tables, 0x20_1000), Some(1));
+
+        // Only the written page gets a copy, the rest stays copy-on-write
+        tables.break_cow(page(0x20_1000)).unwrap();
+        let (entry, entry_level) = tables.lookup(Vaddr::new(0x20_1000)).unwrap();
+        assert_eq!(entry_level, 0);
+        assert!(entry.flags().contains(EntryFlags::WRITE));
+        let copy = translate(&tables, 0x20_1000).unwrap();
+        assert_ne!(copy, BASE + 0x1000);
+
+        let (entry, entry_level) = tables.lookup(Vaddr::new(0x20_2000)).unwrap();
+        assert_eq!(entry_level, 0);
+        assert!(entry.flags().contains(COW));
+        assert!(!entry.flags().contains(EntryFlags::WRITE));
+        assert_eq!(translate(&tables, 0x20_2000), Some(BASE + 0x2000));
+
+        assert_eq!(unsafe { *mem.ptr(Paddr::new(copy)) }, 1);
+        assert_eq!(mem.refcount(frame(BASE + 0x1000)), 1);
+        assert_eq!(mem.refcount(frame(BASE + 0x2000)), 2);
+
+        // The child keeps the megapage
+        let tables = PageTables::new(&mut mem, mode, child);
+        assert_eq!(level(&tables, 0x20_1000), Some(1));
+        assert_eq!(translate(&tables, 0x20_1000), Some(BASE + 0x1000));
+    }
+
+    #[test]
+    fn test_paging_modes_map() {
+        for mode in PagingMode::ALL {
+            let mut mem = SimMemory::new(8);
+            let root = mem.root();
+            let mut tables = PageTables::new(&mut mem, mode, root);
+
+            let vaddr = mode.kernel_start() + 0x5000;
+            tables
+                .map(page(vaddr), frame(0x8000_0000), EntryFlags::RW)
+                .unwrap();
+            assert_eq!(translate(&tables, vaddr + 8), Some(0x8000_0008));
+            assert_eq!(tables.mappings().next().unwrap().vstart, vaddr);
+            assert_eq!(mem.allocated(), mode.levels());
+        }
+    }
+
+    #[test]
+    fn test_translate_access() {
+        use Access::*;
+
+        let mut mem = SimMemory::new(8);
+        let root = mem.root();
+        let mut tables = PageTables::new(&mut mem, PagingMode::Sv39, root);
+
+        let pages = [
+            (0x1000, EntryFlags::RWU),
+            (0x2000, EntryFlags::RW),
+            (0x3000, EntryFlags::RX),
+            (0x4000, EntryFlags::EXECUTE),
+            (0x5000, EntryFlags::RWXU),
+        ];
+        for (vaddr, flags) in pages {
+            tables.map(page(vaddr), frame(vaddr), flags).unwrap();
+        }
+
+        let supervisor = Privilege::default();
+        let user = Privilege {
+            user: true,
+            ..supervisor
+        };
+        let sum = Privilege {
+            sum: true,
+            ..supervisor
+        };
+        let mxr = Privilege {
+            mxr: true,
+            ..supervisor
+        };
+        let check = |vaddr, access, privilege| {
+            tables
+                .translate_access(Vaddr::new(vaddr), access, privilege)
+                .map(|paddr| paddr.inner())
+        };
+
+        // User pages
+        assert_eq!(check(0x1008, Write, user), Ok(0x1008));
+        assert_eq!(check(0x1008, Read, supervisor), Err(PageFault::Denied));
+        assert_eq!(check(0x1008, Write, sum), Ok(0x1008));
+        assert_eq!(check(0x5000, Execute, user), Ok(0x5000));
+        assert_eq!(check(0x5000, Execute, sum), Err(PageFault::Denied));
+
+        // Supervisor pages
+        assert_eq!(check(0x2000, Write, supervisor), Ok(0x2000));
+        assert_eq!(check(0x2000, Read, user), Err(PageFault::Denied));
+        assert_eq!(check(0x2000, Execute, supervisor), Err(PageFault::Denied));
+        assert_eq!(check(0x3000, Write, supervisor), Err(PageFault::Denied));
+        assert_eq!(check(0x3000, Execute, supervisor), Ok(0x3000));
+
+        // Execute-only pages are readable with MXR
+        assert_eq!(check(0x4000, Read, supervisor), Err(PageFault::Denied));
+        assert_eq!(check(0x4000, Read, mxr), Ok(0x4000));
+
+        assert_eq!(check(0x6000, Read, supervisor), Err(PageFault::Invalid));
+        assert_eq!(
+            check(0x40_0000_0000, Read, supervisor),
+            Err(PageFault::NonCanonical)
+        );
+    }
+
+    #[test]
+    fn test_translate_access_entry_bits() {
+        use Access::*;
+
+        let mut mem = SimMemory::new(8);
+        let root = mem.root();
+        let mut tables = PageTables::new(&mut mem, PagingMode::Sv39, root);
+        let supervisor = Privilege::default();
+
+        tables
+            .map(page(0x1000), frame(0x1000), EntryFlags::RW)
+            .unwrap();
+        tables
+            .map_region(0x20_0000, 0x20_0000, 0x20_0000, EntryFlags::RW)
+            .unwrap();
+
+        let set_leaf = |tables: &mut PageTables<SimMemory>, vaddr, flags, paddr| {
+            let mut walk = tables.walk(Vaddr::new(vaddr), MAX_LEVELS).unwrap();
+            let leaf = walk.leaf();
+            *leaf = PageTableEntry::new(EntryFlags::VALID | flags);
+            leaf.set_paddr(Paddr::new(paddr));
+        };
+
+        let check = |tables: &PageTables<SimMemory>, access| {
+            tables
+                .translate_access(Vaddr::new(0x1000), access, supervisor)
+                .map(|paddr| paddr.inner())
+        };
+
+        // The hart does not set A and D itself
+        set_leaf(&mut tables, 0x1000, EntryFlags::RW, 0x1000);
+        assert_eq!(check(&tables, Read), Err(PageFault::NotAccessed));
+        set_leaf(
+            &mut tables,
+            0x1000,
+            EntryFlags::RW | EntryFlags::ACCESSED,
+            0x1000,
+        );
+        assert_eq!(check(&tables, Read), Ok(0x1000));
+        assert_eq!(check(&tables, Write), Err(PageFault::NotDirty));
+
+        // Writable but not readable is reserved
+        set_leaf(
+            &mut tables,
+            0x1000,
+            EntryFlags::WRITE | EntryFlags::ACCESSED | EntryFlags::DIRTY,
+            0x1000,
+        );
+        assert_eq!(check(&tables, Write), Err(PageFault::Reserved));
+
+        // Superpages must be aligned
+        set_leaf(
+            &mut tables,
+            0x20_0000,
+            EntryFlags::RW | EntryFlags::ACCESSED,
+            0x20_1000,
+        );
+        assert_eq!(
+            tables
+                .translate_access(Vaddr::new(0x20_0000), Read, supervisor)
+                .map(|paddr| paddr.inner()),
+            Err(PageFault::MisalignedSuperpage)
+        );
+    }
+}
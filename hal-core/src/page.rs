@@ -1,10 +1,39 @@
-/// Sv39 page table
+use core::{
+    fmt,
+    marker::PhantomData,
+    ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Sub},
+};
+
+use crate::mmu::{IdentityMemory, PhysMemory};
+
+/// Maximum number of page table levels of any supported paging mode
+pub const MAX_LEVELS: usize = 5;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Size of the memory mapped by a leaf entry at `level`: 4 KiB at level 0,
+/// 2 MiB megapages at level 1, 1 GiB gigapages at level 2 and so on.
+pub const fn level_size(level: usize) -> u64 {
+    PAGE_SIZE << (9 * level)
+}
+
+/// Virtual memory system, which determines the number of page table levels
+/// and the width of virtual addresses. Page tables and entries have the same
+/// format in every mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PagingMode {
+    Sv39,
+    Sv48,
+    Sv57,
+}
+
+/// Page table of any paging mode
 #[repr(align(4096))]
 pub struct PageTable {
     entries: [PageTableEntry; 512],
 }
 
-/// Sv39 page table entry
+/// Page table entry of any paging mode
 #[derive(Clone, Copy, Debug)]
 #[repr(transparent)]
 pub struct PageTableEntry(u64);
@@ -27,62 +56,223 @@ pub struct Frame(Paddr);
 #[derive(Clone, Copy, Debug)]
 pub struct Page(Vaddr);
 
-/// Sv39 page table entry flags
-#[repr(u64)]
-#[derive(Debug, Clone)]
-pub enum EntryFlags {
-    Valid = 1 << 0,
-    Read = 1 << 1,
-    Write = 1 << 2,
-    Execute = 1 << 3,
-    User = 1 << 4,
-    Global = 1 << 5,
-    Accessed = 1 << 6,
-    Dirty = 1 << 7,
+/// Set of page table entry flags, the lower 10 bits of an entry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct EntryFlags(u64);
+
+impl EntryFlags {
+    pub const VALID: Self = Self(1 << 0);
+    pub const READ: Self = Self(1 << 1);
+    pub const WRITE: Self = Self(1 << 2);
+    pub const EXECUTE: Self = Self(1 << 3);
+    pub const USER: Self = Self(1 << 4);
+    pub const GLOBAL: Self = Self(1 << 5);
+    pub const ACCESSED: Self = Self(1 << 6);
+    pub const DIRTY: Self = Self(1 << 7);
+    /// Bits reserved for the supervisor's software, ignored by the MMU
+    pub const RSW: Self = Self(0b11 << 8);
 
     // Convenience combinations
-    RW = 1 << 1 | 1 << 2,
-    RX = 1 << 1 | 1 << 3,
-    RWX = 1 << 1 | 1 << 2 | 1 << 3,
-    RWXU = 1 << 1 | 1 << 2 | 1 << 3 | 1 << 4,
-    RWU = 1 << 1 | 1 << 2 | 1 << 4,
+    pub const RW: Self = Self::READ.union(Self::WRITE);
+    pub const RX: Self = Self::READ.union(Self::EXECUTE);
+    pub const RWX: Self = Self::RW.union(Self::EXECUTE);
+    pub const RWXU: Self = Self::RWX.union(Self::USER);
+    pub const RWU: Self = Self::RW.union(Self::USER);
+
+    const ALL: u64 = 0x3ff;
+    const RSW_SHIFT: u64 = 8;
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns `None` if `bits` has anything set beyond the flag bits.
+    pub const fn from_bits(bits: u64) -> Option<Self> {
+        if bits & !Self::ALL != 0 {
+            return None;
+        }
+        Some(Self(bits))
+    }
+
+    /// Drops everything beyond the flag bits, e.g. the PPN of an entry.
+    pub const fn from_bits_truncate(bits: u64) -> Self {
+        Self(bits & Self::ALL)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Whether every flag of `other` is set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether any flag of `other` is set.
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Value of the two software bits
+    pub const fn rsw(self) -> u8 {
+        ((self.0 & Self::RSW.0) >> Self::RSW_SHIFT) as u8
+    }
+
+    pub const fn with_rsw(self, rsw: u8) -> Self {
+        let rsw = (rsw as u64) << Self::RSW_SHIFT;
+        Self(self.difference(Self::RSW).0 | (rsw & Self::RSW.0))
+    }
 }
 
-impl EntryFlags {
-    pub fn as_u64(self) -> u64 {
-        self as u64
+impl BitOr for EntryFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+impl BitOrAssign for EntryFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        *self = self.union(rhs);
     }
+}
+
+impl BitAnd for EntryFlags {
+    type Output = Self;
 
-    pub fn from(bits: u64) -> Self {
-        unsafe { core::mem::transmute(bits) }
+    fn bitand(self, rhs: Self) -> Self {
+        self.intersection(rhs)
+    }
+}
+
+impl BitAndAssign for EntryFlags {
+    fn bitand_assign(&mut self, rhs: Self) {
+        *self = self.intersection(rhs);
+    }
+}
+
+impl Sub for EntryFlags {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        self.difference(rhs)
+    }
+}
+
+impl PagingMode {
+    /// Supported modes, from the largest address space to the smallest
+    pub const ALL: [PagingMode; 3] = [PagingMode::Sv57, PagingMode::Sv48, PagingMode::Sv39];
+
+    pub const fn levels(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+            PagingMode::Sv57 => 5,
+        }
+    }
+
+    /// Value of the MODE field of `satp` that selects this mode
+    pub const fn satp_mode(self) -> u64 {
+        match self {
+            PagingMode::Sv39 => 8,
+            PagingMode::Sv48 => 9,
+            PagingMode::Sv57 => 10,
+        }
+    }
+
+    /// Number of significant bits of a virtual address
+    pub const fn va_bits(self) -> u32 {
+        12 + 9 * self.levels() as u32
+    }
+
+    /// Makes `addr` a canonical address by copying its highest significant
+    /// bit into all bits above it.
+    pub const fn canonical(self, addr: u64) -> Vaddr {
+        let shift = 64 - self.va_bits();
+        Vaddr(((addr << shift) as i64 >> shift) as u64)
+    }
+
+    pub const fn is_canonical(self, vaddr: Vaddr) -> bool {
+        self.canonical(vaddr.0).0 == vaddr.0
+    }
+
+    /// End of the lower canonical half, which belongs to user space
+    pub const fn user_end(self) -> u64 {
+        1 << (self.va_bits() - 1)
+    }
+
+    /// Start of the upper canonical half, which belongs to the kernel. It is
+    /// mapped by the upper half of the entries of the root table.
+    pub const fn kernel_start(self) -> u64 {
+        self.canonical(self.user_end()).0
     }
 }
 
 impl PageTableEntry {
-    pub fn new(flags: u64) -> Self {
-        Self(flags)
+    const PPN_SHIFT: u64 = 10;
+    const PPN_MASK: u64 = ((1 << 44) - 1) << Self::PPN_SHIFT;
+
+    pub const fn new(flags: EntryFlags) -> Self {
+        Self(flags.bits())
+    }
+
+    /// Raw value of the entry
+    pub const fn bits(&self) -> u64 {
+        self.0
     }
 
     pub fn is_valid(&self) -> bool {
-        self.0 & EntryFlags::Valid.as_u64() != 0
+        self.flags().contains(EntryFlags::VALID)
     }
 
     pub fn is_leaf(&self) -> bool {
-        self.0 & EntryFlags::RWX.as_u64() != 0
+        self.flags().intersects(EntryFlags::RWX)
     }
 
-    pub fn flags(&self) -> u64 {
-        // unsafe { core::mem::transmute(self.0 & 0xff) }
-        self.0
+    /// Flag bits of the entry, without the PPN
+    pub fn flags(&self) -> EntryFlags {
+        EntryFlags::from_bits_truncate(self.0)
+    }
+
+    /// Replaces the flags, keeping the PPN
+    pub fn set_flags(&mut self, flags: EntryFlags) {
+        self.0 = (self.0 & !EntryFlags::ALL) | flags.bits();
+    }
+
+    /// Value of the software bits
+    pub fn rsw(&self) -> u8 {
+        self.flags().rsw()
+    }
+
+    pub fn set_rsw(&mut self, rsw: u8) {
+        self.set_flags(self.flags().with_rsw(rsw));
     }
 
     pub fn set_paddr(&mut self, paddr: Paddr) {
-        let ppn = paddr.ppn();
-        self.0 |= ppn << 10;
+        let ppn = paddr.ppn() << Self::PPN_SHIFT;
+        self.0 = (self.0 & !Self::PPN_MASK) | (ppn & Self::PPN_MASK);
     }
 
     pub fn paddr(&self) -> Paddr {
-        let ppn = self.0 >> 10;
+        let ppn = (self.0 & Self::PPN_MASK) >> Self::PPN_SHIFT;
         Paddr(ppn << 12)
     }
 }
@@ -90,32 +280,207 @@ impl PageTableEntry {
 impl PageTable {
     pub fn new() -> Self {
         Self {
-            entries: [PageTableEntry::new(0); 512],
+            entries: [PageTableEntry::new(EntryFlags::empty()); 512],
         }
     }
 
+    pub fn entry(&self, index: usize) -> &PageTableEntry {
+        &self.entries[index]
+    }
+
     pub fn entry_mut(&mut self, index: usize) -> &mut PageTableEntry {
         &mut self.entries[index]
     }
+
+    /// Whether no entry of the table is valid.
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| !entry.is_valid())
+    }
+
+    /// Every mapping of the hierarchy below this root table, in ascending
+    /// order of virtual addresses. Lower level tables are accessed through
+    /// their physical address, so they must be identity mapped.
+    pub fn mappings(&self, mode: PagingMode) -> Mappings<'_> {
+        let root = Paddr::new(self as *const PageTable as u64);
+        Mappings::new(&IdentityMemory, mode, root)
+    }
+}
+
+/// Contiguous run of leaf entries of the same level and flags that map
+/// contiguous physical memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    pub vstart: u64,
+    pub pstart: u64,
+    pub size: u64,
+    pub flags: EntryFlags,
+    pub level: usize,
+}
+
+impl Mapping {
+    pub fn vend(&self) -> u64 {
+        self.vstart + self.size
+    }
+
+    pub fn pend(&self) -> u64 {
+        self.pstart + self.size
+    }
+
+    pub fn contains(&self, vaddr: Vaddr) -> bool {
+        (self.vstart..self.vend()).contains(&vaddr.inner())
+    }
+
+    /// Physical address of `vaddr`, if it lies in the mapping.
+    pub fn translate(&self, vaddr: Vaddr) -> Option<Paddr> {
+        self.contains(vaddr)
+            .then(|| Paddr::new(self.pstart + (vaddr.inner() - self.vstart)))
+    }
+
+    fn continues_with(&self, next: &Mapping) -> bool {
+        self.vend() == next.vstart
+            && self.pend() == next.pstart
+            && self.flags == next.flags
+            && self.level == next.level
+    }
+}
+
+impl fmt::Display for Mapping {
+    /// One line in the style of `/proc/self/maps`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+
+        write!(
+            f,
+            "{:016x}-{:016x} {}{}{}{}{} {:016x} L{} {}",
+            self.vstart,
+            self.vend(),
+            flag(EntryFlags::READ, 'r'),
+            flag(EntryFlags::WRITE, 'w'),
+            flag(EntryFlags::EXECUTE, 'x'),
+            flag(EntryFlags::USER, 'u'),
+            flag(EntryFlags::GLOBAL, 'g'),
+            self.pstart,
+            self.level,
+            self.flags.rsw()
+        )
+    }
+}
+
+/// Iterator over the mappings of a page table hierarchy, see
+/// [`PageTable::mappings`].
+pub struct Mappings<'a, M: PhysMemory = IdentityMemory> {
+    mem: &'a M,
+    mode: PagingMode,
+    // Table and entry index currently visited at each level
+    tables: [Paddr; MAX_LEVELS],
+    indices: [usize; MAX_LEVELS],
+    level: usize,
+    // Run that may still be continued by the next leaf
+    pending: Option<Mapping>,
+    _marker: PhantomData<&'a PageTable>,
+}
+
+impl<'a, M: PhysMemory> Mappings<'a, M> {
+    /// Mappings of the hierarchy below the root table at `root`, whose
+    /// tables are accessed through `mem`.
+    pub fn new(mem: &'a M, mode: PagingMode, root: Paddr) -> Self {
+        let top = mode.levels() - 1;
+        let mut tables = [Paddr::new(0); MAX_LEVELS];
+        tables[top] = root;
+
+        Self {
+            mem,
+            mode,
+            tables,
+            indices: [0; MAX_LEVELS],
+            level: top,
+            pending: None,
+            _marker: PhantomData,
+        }
+    }
+
+    fn next_leaf(&mut self) -> Option<Mapping> {
+        let top = self.mode.levels() - 1;
+
+        loop {
+            let level = self.level;
+            if self.indices[level] == 512 {
+                if level == top {
+                    return None;
+                }
+                self.level += 1;
+                self.indices[self.level] += 1;
+                continue;
+            }
+
+            let table = unsafe { &*self.mem.table(self.tables[level]) };
+            let entry = table.entry(self.indices[level]);
+
+            // Non-leaf entries at level 0 are reserved, so they are skipped
+            // like invalid ones
+            if !entry.is_valid() || (level == 0 && !entry.is_leaf()) {
+                self.indices[level] += 1;
+                continue;
+            }
+
+            if entry.is_leaf() {
+                let vaddr = (level..=top)
+                    .map(|lv| (self.indices[lv] as u64) << (12 + 9 * lv))
+                    .sum();
+                self.indices[level] += 1;
+
+                return Some(Mapping {
+                    vstart: self.mode.canonical(vaddr).inner(),
+                    pstart: entry.paddr().inner(),
+                    size: level_size(level),
+                    flags: entry.flags(),
+                    level,
+                });
+            }
+
+            self.level -= 1;
+            self.tables[self.level] = entry.paddr();
+            self.indices[self.level] = 0;
+        }
+    }
+}
+
+impl<M: PhysMemory> Iterator for Mappings<'_, M> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        while let Some(leaf) = self.next_leaf() {
+            match &mut self.pending {
+                Some(run) if run.continues_with(&leaf) => run.size += leaf.size,
+                Some(run) => return Some(core::mem::replace(run, leaf)),
+                None => self.pending = Some(leaf),
+            }
+        }
+
+        self.pending.take()
+    }
 }
 
 impl Vaddr {
-    pub fn new(addr: u64) -> Self {
-        // Make bits 39-63 copy of bit 38 to form a canonical address
-        Self(((addr << 25) as i64 >> 25) as u64)
+    /// Wraps `addr` as is. Addresses with any of the upper bits set must be
+    /// made canonical for the paging mode with [`PagingMode::canonical`].
+    pub const fn new(addr: u64) -> Self {
+        Self(addr)
     }
 
     pub fn offset(&self) -> u64 {
         self.0 & 0xfff
     }
 
-    pub fn indexed_vpn(self) -> [usize; 3] {
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    /// Page table indices of the address, starting at level 0. Modes with
+    /// fewer levels ignore the upper entries.
+    pub fn indexed_vpn(self) -> [usize; MAX_LEVELS] {
         let vpns = self.0 >> 12;
-        [
-            (vpns & 0x1ff) as usize,
-            ((vpns >> 9) & 0x1ff) as usize,
-            ((vpns >> 18) & 0x1ff) as usize,
-        ]
+        core::array::from_fn(|level| ((vpns >> (9 * level)) & 0x1ff) as usize)
     }
 
     pub fn inner(&self) -> u64 {
@@ -152,6 +517,24 @@ impl Frame {
     }
 }
 
+/// Allocator of physical frames. Frames are reference counted so that the
+/// same frame can be mapped more than once; it is freed when the last
+/// reference is released.
+pub trait FrameAllocator {
+    /// Allocates a zeroed frame with a reference count of one.
+    fn allocate_frame(&mut self) -> Option<Frame>;
+
+    /// Adds a reference to an allocated frame.
+    fn retain_frame(&mut self, frame: Frame);
+
+    /// Drops a reference to `frame` and frees it once none are left. Returns
+    /// `true` if the frame was freed.
+    fn release_frame(&mut self, frame: Frame) -> bool;
+
+    /// Number of references to `frame`, zero if it is free.
+    fn refcount(&self, frame: Frame) -> u16;
+}
+
 impl Page {
     pub fn containing_address(addr: u64) -> Self {
         Page(Vaddr(addr & !0xfff))
@@ -216,9 +599,141 @@ impl Iterator for FrameRange {
 mod tests {
     use super::*;
 
+    use std::{boxed::Box, vec::Vec};
+
     #[test]
     fn test_page_table_entry() {
-        let entry = PageTableEntry::new(EntryFlags::Valid as u64);
+        let entry = PageTableEntry::new(EntryFlags::VALID);
         assert!(entry.is_valid());
     }
+
+    #[test]
+    fn test_entry_flags() {
+        let flags = EntryFlags::RW | EntryFlags::USER;
+        assert_eq!(flags, EntryFlags::RWU);
+        assert!(flags.contains(EntryFlags::WRITE));
+        assert!(!flags.contains(EntryFlags::RWX));
+        assert!(flags.intersects(EntryFlags::RX));
+        assert_eq!(flags & EntryFlags::RX, EntryFlags::READ);
+        assert_eq!(
+            flags - EntryFlags::WRITE,
+            EntryFlags::READ | EntryFlags::USER
+        );
+
+        assert_eq!(EntryFlags::from_bits(1 << 10), None);
+        assert_eq!(
+            EntryFlags::from_bits(0b11),
+            Some(EntryFlags::VALID | EntryFlags::READ)
+        );
+    }
+
+    // Points `entry` at `table`, whose address doubles as physical address
+    fn link(entry: &mut PageTableEntry, table: &PageTable) {
+        *entry = PageTableEntry::new(EntryFlags::VALID);
+        entry.set_paddr(Paddr::new(table as *const PageTable as u64));
+    }
+
+    fn leaf(paddr: u64, flags: EntryFlags) -> PageTableEntry {
+        let mut entry = PageTableEntry::new(EntryFlags::VALID | flags);
+        entry.set_paddr(Paddr::new(paddr));
+        entry
+    }
+
+    #[test]
+    fn test_mappings() {
+        let mut root = Box::new(PageTable::new());
+        let mut l1 = Box::new(PageTable::new());
+        let mut l0 = Box::new(PageTable::new());
+
+        // Two contiguous pages, a gap, and a page with other flags
+        *l0.entry_mut(0) = leaf(0x8000_0000, EntryFlags::RX);
+        *l0.entry_mut(1) = leaf(0x8000_1000, EntryFlags::RX);
+        *l0.entry_mut(3) = leaf(0x8000_3000, EntryFlags::RW);
+        link(l1.entry_mut(0), &l0);
+        // Two megapages following the page table, then a gigapage at the
+        // top of the address space
+        *l1.entry_mut(1) = leaf(0x8020_0000, EntryFlags::RW);
+        *l1.entry_mut(2) = leaf(0x8040_0000, EntryFlags::RW);
+        link(root.entry_mut(0), &l1);
+        *root.entry_mut(511) = leaf(0x4000_0000, EntryFlags::RW | EntryFlags::GLOBAL);
+
+        let mappings: Vec<_> = root.mappings(PagingMode::Sv39).collect();
+        let mapping = |vstart, pstart, size, flags, level| Mapping {
+            vstart,
+            pstart,
+            size,
+            flags: EntryFlags::VALID | flags,
+            level,
+        };
+
+        assert_eq!(
+            mappings,
+            [
+                mapping(0x0, 0x8000_0000, 0x2000, EntryFlags::RX, 0),
+                mapping(0x3000, 0x8000_3000, 0x1000, EntryFlags::RW, 0),
+                mapping(0x20_0000, 0x8020_0000, 0x40_0000, EntryFlags::RW, 1),
+                mapping(
+                    0xffff_ffff_c000_0000,
+                    0x4000_0000,
+                    0x4000_0000,
+                    EntryFlags::RW | EntryFlags::GLOBAL,
+                    2
+                ),
+            ]
+        );
+
+        assert_eq!(
+            mappings[2]
+                .translate(Vaddr::new(0x30_1234))
+                .map(|p| p.inner()),
+            Some(0x8030_1234)
+        );
+        assert!(mappings[0].translate(Vaddr::new(0x2000)).is_none());
+        assert_eq!(
+            std::format!("{}", mappings[0]),
+            "0000000000000000-0000000000002000 r-x-- 0000000080000000 L0 0"
+        );
+    }
+
+    #[test]
+    fn test_entry_flags_without_ppn() {
+        let mut entry = PageTableEntry::new(EntryFlags::VALID | EntryFlags::RW);
+        entry.set_paddr(Paddr::new(0x8020_3000));
+        entry.set_rsw(0b10);
+
+        assert_eq!(entry.rsw(), 0b10);
+        assert_eq!(entry.paddr().inner(), 0x8020_3000);
+        assert_eq!(
+            entry.flags(),
+            (EntryFlags::VALID | EntryFlags::RW).with_rsw(0b10)
+        );
+
+        entry.set_flags(EntryFlags::VALID | EntryFlags::READ);
+        assert_eq!(entry.rsw(), 0);
+        assert_eq!(entry.paddr().inner(), 0x8020_3000);
+    }
+
+    #[test]
+    fn test_paging_modes() {
+        let addr = 0x40_0000_0000;
+        assert_eq!(
+            PagingMode::Sv39.canonical(addr).inner(),
+            0xffff_ffc0_0000_0000
+        );
+        assert_eq!(PagingMode::Sv48.canonical(addr).inner(), addr);
+        assert!(!PagingMode::Sv39.is_canonical(Vaddr::new(addr)));
+        assert!(PagingMode::Sv57.is_canonical(Vaddr::new(addr)));
+
+        let vpn = Vaddr::new(0x0123_4567_89ab_c000).indexed_vpn();
+        assert_eq!(vpn, [0x0bc, 0x04d, 0x19e, 0x08a, 0x123]);
+        assert_eq!(PagingMode::Sv57.va_bits(), 57);
+
+        assert_eq!(PagingMode::Sv39.user_end(), 0x40_0000_0000);
+        assert_eq!(PagingMode::Sv39.kernel_start(), 0xffff_ffc0_0000_0000);
+        assert_eq!(PagingMode::Sv48.kernel_start(), 0xffff_8000_0000_0000);
+        assert_eq!(
+            Vaddr::new(PagingMode::Sv48.kernel_start()).indexed_vpn()[3],
+            256
+        );
+    }
 }
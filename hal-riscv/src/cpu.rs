@@ -125,6 +125,7 @@ pub struct Sip {
     pub stip: u8,
 }
 
+#[derive(Default, Clone)]
 pub struct Sie {
     pub ssie: u8,
     pub stie: u8,
@@ -133,13 +134,41 @@ pub struct Sie {
 #[derive(Debug, Clone)]
 pub struct Satp {
     mode: u64,
+    asid: u16,
     ppn: u64,
 }
 
 impl Satp {
+    const ASID_SHIFT: u64 = 44;
+
     pub fn new(mode: u64, addr: usize) -> Self {
         let ppn = (addr >> 12) as u64;
-        Self { mode, ppn }
+        Self { mode, asid: 0, ppn }
+    }
+
+    /// Decodes a value read from satp
+    pub fn from_bits(bits: u64) -> Self {
+        Self {
+            mode: bits >> 60,
+            asid: (bits >> Self::ASID_SHIFT) as u16,
+            ppn: bits & ((1 << Self::ASID_SHIFT) - 1),
+        }
+    }
+
+    pub fn with_asid(self, asid: u16) -> Self {
+        Self { asid, ..self }
+    }
+
+    pub fn mode(&self) -> u64 {
+        self.mode
+    }
+
+    pub fn asid(&self) -> u16 {
+        self.asid
+    }
+
+    pub fn bits(&self) -> u64 {
+        (self.mode << 60) | ((self.asid as u64) << Self::ASID_SHIFT) | self.ppn
     }
 }
 
@@ -175,14 +204,43 @@ pub fn read_sie() -> Sie {
     }
 }
 
+/// Switches the address space without flushing any cached translations,
+/// which is up to the caller depending on how the ASID was used before.
 #[inline(always)]
 pub fn write_satp(satp: Satp) {
-    let satp = (satp.mode << 60) | satp.ppn;
     unsafe {
         asm!(
             "csrw satp, {}",
-            "sfence.vma x0, x0",
-            in(reg) satp
+            in(reg) satp.bits()
+        )
+    }
+}
+
+/// Flushes the cached translations of `vaddr` in all address spaces. Only
+/// leaf entries are covered, use [`sfence_vma_all`] after freeing page tables.
+#[inline(always)]
+pub fn sfence_vma(vaddr: usize) {
+    unsafe {
+        asm!(
+            "sfence.vma {}, zero",
+            in(reg) vaddr
+        )
+    }
+}
+
+#[inline(always)]
+pub fn sfence_vma_all() {
+    unsafe { asm!("sfence.vma zero, zero") }
+}
+
+/// Flushes all cached translations of the address space `asid`, apart from
+/// global mappings.
+#[inline(always)]
+pub fn sfence_vma_asid(asid: u16) {
+    unsafe {
+        asm!(
+            "sfence.vma zero, {}",
+            in(reg) asid as usize
         )
     }
 }
@@ -415,6 +473,17 @@ pub fn write_mie(mie: Mie) {
     }
 }
 
+#[inline(always)]
+pub fn write_sie(sie: Sie) {
+    let sie = (sie.ssie as u64) << 1 | (sie.stie as u64) << 5;
+    unsafe {
+        asm!(
+            "csrw sie, {}",
+            in(reg) sie
+        )
+    }
+}
+
 #[inline(always)]
 pub fn write_mip(mip: Mip) {
     let mip = (mip.ssip as u64) << 1
@@ -514,6 +583,16 @@ pub fn write_mtvec(fun: fn()) {
     }
 }
 
+#[inline(always)]
+pub fn write_stvec(fun: fn()) {
+    unsafe {
+        asm!(
+            "csrw stvec, {0}",
+            in(reg) fun
+        )
+    }
+}
+
 #[inline(always)]
 pub fn write_stvec_vectored(addr: fn()) {
     unsafe {
@@ -554,6 +633,18 @@ pub fn set_sstatus(sstatus: Sstatus) {
     }
 }
 
+#[inline(always)]
+pub fn clear_sstatus(sstatus: Sstatus) {
+    let sstatus =
+        (sstatus.sie as u64) << 1 | (sstatus.spie as u64) << 5 | (sstatus.spp as u64) << 8;
+    unsafe {
+        asm!(
+            "csrc sstatus, {}",
+            in(reg) sstatus
+        )
+    }
+}
+
 #[inline(always)]
 pub fn read_scause() -> Cause {
     let scause: u64;
@@ -594,10 +685,11 @@ impl fmt::Display for Satp {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "satp ::: mode: {}, ppn: 0x{:x} (0x{:x})",
+            "satp ::: mode: {}, asid: {}, ppn: 0x{:x} (0x{:x})",
             self.mode,
+            self.asid,
             self.ppn,
-            self.mode << 60 | self.ppn
+            self.bits()
         )
     }
 }
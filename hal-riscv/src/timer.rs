@@ -19,3 +19,24 @@ pub fn write_mtimecmp(mtime: u64) {
         asm!("sd {0}, 0({1})", in(reg) mtime, in(reg) RISCV_MTIMECMP_ADDR);
     }
 }
+
+/// Reads the time CSR, which M-mode has to make accessible through mcounteren
+#[inline(always)]
+pub fn read_time() -> u64 {
+    let time: u64;
+    unsafe {
+        asm!("csrr {}, time", out(reg) time);
+    }
+
+    time
+}
+
+/// Raises the supervisor timer interrupt once the time reaches `time`. Needs
+/// the Sstc extension, enabled through menvcfg.
+#[inline(always)]
+pub fn write_stimecmp(time: u64) {
+    unsafe {
+        // stimecmp
+        asm!("csrw 0x14d, {}", in(reg) time);
+    }
+}
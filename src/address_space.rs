@@ -0,0 +1,599 @@
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use hal_core::asid::{Asid, AsidAllocator, Flush};
+use hal_core::mmu::PhysMemory;
+use hal_core::page::{
+    EntryFlags, Frame, Mapping, Mappings, Paddr, Page, PageTable, Vaddr, PAGE_SIZE,
+};
+use hal_riscv::cpu::{sfence_vma, sfence_vma_all, sfence_vma_asid, Satp};
+use once_cell::unsync::OnceCell;
+
+use crate::alloc::Locked;
+use crate::constants::MMAP_BEGIN_VADDR;
+use crate::frame::{alloc_frame, release_frame, try_alloc_frame};
+use crate::page::{self, paging_mode, KernelMemory, MapError};
+use crate::serial_debug;
+use crate::shm::SharedMemory;
+
+pub use hal_core::mmu::{Access, COW, OWNED};
+
+/// Virtual memory area of anonymous memory. Its pages are only backed by
+/// zeroed frames when they are first accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub flags: EntryFlags,
+}
+
+impl Vma {
+    pub fn contains(&self, vaddr: usize) -> bool {
+        (self.start..self.end).contains(&vaddr)
+    }
+}
+
+/// Shared memory object mapped in full at `start`
+#[derive(Debug, Clone)]
+pub struct SharedMapping {
+    pub start: usize,
+    pub object: Arc<SharedMemory>,
+}
+
+impl SharedMapping {
+    pub fn end(&self) -> usize {
+        self.start + self.object.size()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The address does not belong to any area
+    NoArea,
+    /// The area does not allow the access, or the page is already mapped and
+    /// the fault was caused by its flags
+    AccessDenied,
+    OutOfFrames,
+}
+
+/// Entries of a root table that map the kernel's upper half
+const KERNEL_ENTRIES: Range<usize> = 256..512;
+
+static KERNEL_ROOT: Locked<OnceCell<Frame>> = Locked::new(OnceCell::new());
+
+static ASIDS: Locked<OnceCell<AsidAllocator>> = Locked::new(OnceCell::new());
+
+/// Sets up ASID allocation for as many ASID bits as the hart implements and
+/// returns their number. The boot code wrote ones to all of them, and only
+/// the implemented ones stuck in `probed`.
+pub fn init_asids(probed: &Satp) -> u32 {
+    let bits = probed.asid().count_ones();
+
+    ASIDS
+        .lock()
+        .set(AsidAllocator::new(bits))
+        .expect("ASID allocator already initialized");
+
+    bits
+}
+
+// Runs `f` on the root table of the kernel's address space, whose tables
+// below the kernel half are shared by every address space
+fn with_kernel_root<T>(f: impl FnOnce(&mut PageTable) -> T) -> T {
+    let root = KERNEL_ROOT.lock();
+    let root = root.get().expect("Kernel address space not set");
+    f(unsafe { &mut *KernelMemory.table(root.addr()) })
+}
+
+/// Removes `page` from the kernel half of every address space and returns
+/// the frame it was mapped to. A superpage around it must not be mapped by a
+/// root entry, since every address space has its own copy of those.
+pub fn unmap_kernel_page(page: Page) -> Result<Option<Frame>, MapError> {
+    with_kernel_root(|root| page::unmap(root, page))
+}
+
+/// Maps `page` to `frame` in the kernel half of every address space.
+pub fn map_kernel_page(page: Page, frame: Frame, flags: EntryFlags) -> Result<(), MapError> {
+    with_kernel_root(|root| page::try_map(root, page, frame, flags))
+}
+
+/// Page table hierarchy that owns its tables and the frames it allocated.
+/// Dropping it returns all of them to the frame allocator.
+#[derive(Debug)]
+pub struct AddressSpace {
+    root: Frame,
+    // Assigned when the address space is first activated
+    asid: Option<Asid>,
+    // Sorted by start address, never overlapping
+    vmas: Vec<Vma>,
+    // Never overlapping with each other or with areas. Their frames are
+    // borrowed from the objects, which they keep alive.
+    shared: Vec<SharedMapping>,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user half. The kernel half is
+    /// shared with the kernel's address space, if there is one yet.
+    pub fn new() -> Self {
+        let root = alloc_frame();
+        serial_debug!("Allocated root page table at 0x{:x}", root.addr().inner());
+
+        let mut space = Self {
+            root,
+            asid: None,
+            vmas: Vec::new(),
+            shared: Vec::new(),
+        };
+
+        if let Some(kernel) = KERNEL_ROOT.lock().get() {
+            let kernel = unsafe { &*KernelMemory.table(kernel.addr()) };
+            for i in KERNEL_ENTRIES {
+                *space.root_mut().entry_mut(i) = *kernel.entry(i);
+            }
+        }
+
+        space
+    }
+
+    /// Makes the kernel half of this address space that of every address
+    /// space created afterwards. The tables below the root are shared, so
+    /// new mappings show up everywhere as long as they do not need a new
+    /// root entry.
+    pub fn share_kernel_half(&self) {
+        KERNEL_ROOT
+            .lock()
+            .set(self.root)
+            .expect("Kernel address space already set");
+    }
+
+    pub fn root(&self) -> &PageTable {
+        unsafe { &*KernelMemory.table(self.root.addr()) }
+    }
+
+    pub fn root_mut(&mut self) -> &mut PageTable {
+        unsafe { &mut *KernelMemory.table(self.root.addr()) }
+    }
+
+    pub fn satp(&self) -> Satp {
+        let asid = self.asid.map_or(0, |asid| asid.value());
+        Satp::new(paging_mode().satp_mode(), self.root.addr().inner() as usize).with_asid(asid)
+    }
+
+    /// Switches the hart to this address space. Cached translations are only
+    /// flushed if its ASID may have been used by another address space.
+    pub fn activate(&mut self) {
+        let flush = ASIDS
+            .lock()
+            .get_mut()
+            .expect("ASID allocator not initialized")
+            .assign(&mut self.asid);
+
+        let satp = self.satp();
+        let asid = satp.asid();
+        hal_riscv::cpu::write_satp(satp);
+
+        match flush {
+            Flush::None => {}
+            Flush::Asid => sfence_vma_asid(asid),
+            Flush::All => sfence_vma_all(),
+        }
+    }
+
+    /// Maps `page` to `frame`, which stays owned by the caller.
+    pub fn map(&mut self, page: Page, frame: Frame, flags: EntryFlags) -> Result<(), MapError> {
+        page::try_map(self.root_mut(), page, frame, flags - OWNED)
+    }
+
+    /// Maps `size` bytes of memory owned by the caller, using superpages
+    /// where possible. Both addresses must be page aligned, `size` is rounded
+    /// up to whole pages.
+    pub fn map_range(
+        &mut self,
+        vstart: usize,
+        pstart: usize,
+        size: usize,
+        flags: EntryFlags,
+    ) -> Result<(), MapError> {
+        let size = (size as u64).next_multiple_of(PAGE_SIZE);
+        page::map_region(
+            self.root_mut(),
+            vstart as u64,
+            pstart as u64,
+            size,
+            flags - OWNED,
+        )
+    }
+
+    /// Maps `page` to a fresh zeroed frame owned by the address space.
+    pub fn map_alloc(&mut self, page: Page, flags: EntryFlags) -> Result<Frame, MapError> {
+        let frame = try_alloc_frame().ok_or(MapError::OutOfFrames)?;
+        page::try_map(self.root_mut(), page, frame, flags | OWNED).inspect_err(|_| {
+            release_frame(frame);
+        })?;
+
+        Ok(frame)
+    }
+
+    /// Maps every page in `start..end` to fresh frames. Both addresses must
+    /// be page aligned.
+    pub fn map_alloc_range(
+        &mut self,
+        start: usize,
+        end: usize,
+        flags: EntryFlags,
+    ) -> Result<(), MapError> {
+        for vaddr in (start..end).step_by(PAGE_SIZE as usize) {
+            self.map_alloc(Page::containing_address(vaddr as u64), flags)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn unmap(&mut self, page: Page) -> Result<(), MapError> {
+        let start = page.addr().inner() as usize;
+        self.unmap_range(start, start + PAGE_SIZE as usize)
+    }
+
+    /// Removes every mapping in `start..end` and releases the frames owned
+    /// by the address space. Both addresses must be page aligned. Fails if a
+    /// superpage that lies partly in the range cannot be split, in which case
+    /// the pages before it have already been removed.
+    pub fn unmap_range(&mut self, start: usize, end: usize) -> Result<(), MapError> {
+        let (first, last) = (start as u64, end as u64);
+
+        let mut owned: Vec<u64> = self
+            .user_mappings()
+            .filter(|m| m.flags.contains(OWNED) && m.vstart < last && m.vend() > first)
+            .flat_map(|mapping| {
+                let vstart = mapping.vstart.max(first);
+                let vend = mapping.vend().min(last);
+                (vstart..vend)
+                    .step_by(PAGE_SIZE as usize)
+                    .map(move |vaddr| mapping.pstart + (vaddr - mapping.vstart))
+            })
+            .collect();
+        owned.sort_unstable();
+
+        let mut unmapped = Vec::new();
+        let result = page::unmap_range(self.root_mut(), start, end, |frame| unmapped.push(frame));
+
+        for frame in unmapped {
+            if owned.binary_search(&frame.addr().inner()).is_ok() {
+                release_frame(frame);
+            }
+        }
+        result
+    }
+
+    pub fn translate(&self, vaddr: Vaddr) -> Option<Paddr> {
+        page::translate_vaddr(self.root(), vaddr)
+    }
+
+    pub fn mappings(&self) -> Mappings<'_, KernelMemory> {
+        page::mappings(self.root())
+    }
+
+    fn user_mappings(&self) -> impl Iterator<Item = Mapping> + '_ {
+        let user_end = paging_mode().user_end();
+        self.mappings().take_while(move |m| m.vstart < user_end)
+    }
+
+    /// Registers an area of anonymous memory at `start..end`, which must be
+    /// page aligned. Nothing is mapped until the pages are accessed.
+    pub fn add_vma(&mut self, start: usize, end: usize, flags: EntryFlags) -> Result<(), MapError> {
+        assert!(
+            start < end
+                && start.is_multiple_of(PAGE_SIZE as usize)
+                && end.is_multiple_of(PAGE_SIZE as usize)
+                && page::is_user_address(end - 1),
+            "Invalid area 0x{:x} - 0x{:x}",
+            start,
+            end
+        );
+
+        if self.overlaps(start, end) {
+            return Err(MapError::Overlap);
+        }
+
+        let index = self.vmas.partition_point(|vma| vma.start < start);
+        self.vmas.insert(index, Vma { start, end, flags });
+        Ok(())
+    }
+
+    // Whether `start..end` overlaps an area or a shared mapping
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        let index = self.vmas.partition_point(|vma| vma.start < start);
+        let overlaps_prev = index > 0 && self.vmas[index - 1].end > start;
+        let overlaps_next = self.vmas.get(index).is_some_and(|vma| vma.start < end);
+
+        overlaps_prev
+            || overlaps_next
+            || self
+                .shared
+                .iter()
+                .any(|mapping| mapping.start < end && mapping.end() > start)
+    }
+
+    pub fn find_vma(&self, vaddr: usize) -> Option<&Vma> {
+        let index = self.vmas.partition_point(|vma| vma.end <= vaddr);
+        self.vmas.get(index).filter(|vma| vma.contains(vaddr))
+    }
+
+    pub fn vmas(&self) -> &[Vma] {
+        &self.vmas
+    }
+
+    // Splits the area containing `vaddr` in two at `vaddr`
+    fn split_vma(&mut self, vaddr: usize) {
+        let index = self.vmas.partition_point(|vma| vma.end <= vaddr);
+        let Some(&vma) = self.vmas.get(index).filter(|vma| vma.start < vaddr) else {
+            return;
+        };
+
+        self.vmas[index].end = vaddr;
+        self.vmas.insert(
+            index + 1,
+            Vma {
+                start: vaddr,
+                ..vma
+            },
+        );
+    }
+
+    /// First address at or above `from` where `size` bytes are free in the
+    /// user half. Areas, shared mappings and pages mapped outside of them all
+    /// count as taken.
+    pub fn find_free_range(&self, from: usize, size: usize) -> Option<usize> {
+        let mut taken: Vec<Range<usize>> = self
+            .vmas
+            .iter()
+            .map(|vma| vma.start..vma.end)
+            .chain(
+                self.shared
+                    .iter()
+                    .map(|mapping| mapping.start..mapping.end()),
+            )
+            .chain(
+                self.user_mappings()
+                    .map(|mapping| mapping.vstart as usize..mapping.vend() as usize),
+            )
+            .collect();
+        taken.sort_unstable_by_key(|range| range.start);
+
+        let mut start = from;
+        for range in taken {
+            if range.start >= start.saturating_add(size) {
+                break;
+            }
+            start = start.max(range.end);
+        }
+
+        let end = start.checked_add(size)?;
+        (size > 0 && page::is_user_address(end - 1)).then_some(start)
+    }
+
+    /// Start of a free range of `size` bytes for a new mapping: `addr` unless
+    /// that is zero, and the first free range from `MMAP_BEGIN_VADDR`
+    /// otherwise.
+    pub fn place(&self, addr: usize, size: usize) -> Result<usize, MapError> {
+        if addr == 0 {
+            self.find_free_range(MMAP_BEGIN_VADDR as usize, size)
+                .ok_or(MapError::NoSpace)
+        } else if self.find_free_range(addr, size) == Some(addr) {
+            Ok(addr)
+        } else {
+            Err(MapError::Overlap)
+        }
+    }
+
+    /// Adds an area of `size` bytes of anonymous memory, rounded up to whole
+    /// pages, at the range chosen by [`AddressSpace::place`] and returns its
+    /// start.
+    pub fn mmap(&mut self, addr: usize, size: usize, flags: EntryFlags) -> Result<usize, MapError> {
+        let size = size.next_multiple_of(PAGE_SIZE as usize);
+        let start = self.place(addr, size)?;
+
+        self.add_vma(start, start + size, flags)?;
+        Ok(start)
+    }
+
+    /// Removes the areas in `start..end`, splitting those that only partly
+    /// lie in it, and every mapping in it. Both addresses must be page
+    /// aligned. Shared mappings are removed with `unmap_shared` only. The
+    /// areas stay if the mappings cannot all be removed.
+    pub fn munmap(&mut self, start: usize, end: usize) -> Result<(), MapError> {
+        if self
+            .shared
+            .iter()
+            .any(|mapping| mapping.start < end && mapping.end() > start)
+        {
+            return Err(MapError::Overlap);
+        }
+
+        self.unmap_range(start, end)?;
+        self.split_vma(start);
+        self.split_vma(end);
+        self.vmas.retain(|vma| vma.end <= start || vma.start >= end);
+        Ok(())
+    }
+
+    /// Changes the flags of the areas in `start..end` and of the pages mapped
+    /// in them. The range must be covered by areas and page aligned.
+    /// Copy-on-write pages stay read-only until they are written.
+    pub fn mprotect(
+        &mut self,
+        start: usize,
+        end: usize,
+        flags: EntryFlags,
+    ) -> Result<(), MapError> {
+        let mut covered = start;
+        let first = self.vmas.partition_point(|vma| vma.end <= start);
+        for vma in &self.vmas[first..] {
+            if covered >= end || vma.start > covered {
+                break;
+            }
+            covered = vma.end;
+        }
+        if covered < end {
+            return Err(MapError::NotMapped);
+        }
+
+        self.split_vma(start);
+        self.split_vma(end);
+        self.vmas
+            .iter_mut()
+            .filter(|vma| vma.start >= start && vma.end <= end)
+            .for_each(|vma| vma.flags = flags);
+
+        page::protect_cow(self.root_mut(), start, end, flags)?;
+        Ok(())
+    }
+
+    /// Maps all of `object` at `start`, which must be page aligned. Other
+    /// address spaces may map it elsewhere and with other flags.
+    pub fn map_shared(
+        &mut self,
+        object: &Arc<SharedMemory>,
+        start: usize,
+        flags: EntryFlags,
+    ) -> Result<(), MapError> {
+        let end = start + object.size();
+        assert!(
+            start.is_multiple_of(PAGE_SIZE as usize) && page::is_user_address(end - 1),
+            "Invalid shared mapping 0x{:x} - 0x{:x}",
+            start,
+            end
+        );
+
+        // Pages mapped outside of areas, e.g. the UART, are taken as well
+        if self.find_free_range(start, object.size()) != Some(start) {
+            return Err(MapError::Overlap);
+        }
+
+        for (i, &frame) in object.frames().iter().enumerate() {
+            let vaddr = start + i * PAGE_SIZE as usize;
+            let page = Page::containing_address(vaddr as u64);
+            if let Err(err) = self.map(page, frame, flags - EntryFlags::RSW) {
+                return self.unmap_range(start, vaddr).and(Err(err));
+            }
+        }
+
+        self.shared.push(SharedMapping {
+            start,
+            object: object.clone(),
+        });
+        Ok(())
+    }
+
+    /// Removes the shared mapping at `start`, which frees the object if it
+    /// was its last mapping.
+    pub fn unmap_shared(&mut self, start: usize) -> Result<(), MapError> {
+        let index = self
+            .shared
+            .iter()
+            .position(|mapping| mapping.start == start)
+            .ok_or(MapError::NotMapped)?;
+
+        let end = self.shared[index].end();
+        self.unmap_range(start, end)?;
+        self.shared.swap_remove(index);
+        Ok(())
+    }
+
+    pub fn shared_mappings(&self) -> &[SharedMapping] {
+        &self.shared
+    }
+
+    /// Duplicates the address space. Owned frames are shared instead of
+    /// copied, writable ones as copy-on-write in both address spaces.
+    /// Shared memory stays shared.
+    pub fn clone_cow(&mut self) -> Result<AddressSpace, MapError> {
+        let mut child = AddressSpace::new();
+        child.vmas = self.vmas.clone();
+        child.shared = self.shared.clone();
+
+        let user_end = paging_mode().user_end();
+        page::share_cow(self.root_mut(), child.root.addr(), 0, user_end)?;
+
+        Ok(child)
+    }
+
+    /// Resolves a fault at `vaddr`. Writes to copy-on-write pages of a
+    /// writable area get a private copy, and unmapped pages of an area that
+    /// allows `access` are backed with a zeroed frame.
+    pub fn handle_page_fault(&mut self, vaddr: usize, access: Access) -> Result<(), FaultError> {
+        let page = Page::containing_address(vaddr as u64);
+        if let Some((entry, _)) = page::lookup(self.root(), page.addr()) {
+            let writable = self
+                .find_vma(vaddr)
+                .is_some_and(|vma| vma.flags.contains(EntryFlags::WRITE));
+            if access == Access::Write && entry.flags().contains(COW) && writable {
+                return page::break_cow(self.root_mut(), page).map_err(|_| FaultError::OutOfFrames);
+            }
+            return Err(FaultError::AccessDenied);
+        }
+
+        let vma = *self.find_vma(vaddr).ok_or(FaultError::NoArea)?;
+        if !vma.flags.contains(access.flag()) {
+            return Err(FaultError::AccessDenied);
+        }
+
+        match self.map_alloc(page, vma.flags) {
+            Ok(_) => {}
+            Err(MapError::OutOfFrames) => return Err(FaultError::OutOfFrames),
+            Err(_) => return Err(FaultError::AccessDenied),
+        }
+
+        // Invalid entries may be cached as well
+        sfence_vma(vaddr);
+        Ok(())
+    }
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Releases every table below the `entries` of `table`, which is at `level`
+fn release_tables(table: &PageTable, level: usize, entries: Range<usize>) {
+    if level == 0 {
+        return;
+    }
+
+    for i in entries {
+        let entry = table.entry(i);
+        if !entry.is_valid() || entry.is_leaf() {
+            continue;
+        }
+
+        let child = entry.paddr();
+        release_tables(
+            unsafe { &*KernelMemory.table(child) },
+            level - 1,
+            0..512,
+        );
+        release_frame(Frame::containing_address(child.inner()));
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        for mapping in self.user_mappings().filter(|m| m.flags.contains(OWNED)) {
+            for offset in (0..mapping.size).step_by(PAGE_SIZE as usize) {
+                release_frame(Frame::containing_address(mapping.pstart + offset));
+            }
+        }
+
+        // The kernel half is shared and stays
+        release_tables(
+            self.root(),
+            paging_mode().levels() - 1,
+            0..KERNEL_ENTRIES.start,
+        );
+        release_frame(self.root);
+    }
+}
@@ -1,12 +1,24 @@
 extern crate alloc;
 
-use crate::{ALLOC_START, HEAP_SIZE, HEAP_START};
+use crate::page::phys_to_virt;
+use crate::trap::Task;
+use crate::{ALLOC_SIZE, ALLOC_START, BOOT_HEAP_SIZE, HEAP_SIZE, HEAP_START, MEMORY_END};
 
-use allocator::buddy::BuddyAllocator;
-use core::alloc::{GlobalAlloc, Layout};
+use allocator::arena;
+use allocator::buddy::Stats;
+use allocator::bump::BumpAllocator;
+#[cfg(feature = "debug-alloc")]
+use allocator::debug::{Corruption, Guarded};
+use allocator::heap::Heap;
+use allocator::slab::PAGE_SIZE;
+use allocator::slab::{ObjectCache, SlabAllocator};
+use core::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use hal_core::page::Paddr;
 use once_cell::unsync::OnceCell;
 
 const MIN_BLOCK_SIZE: usize = 64;
+const MAX_HEAP_REGIONS: usize = 4;
 
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
@@ -24,74 +36,354 @@ impl<A> Locked<A> {
     }
 }
 
-unsafe impl GlobalAlloc for Locked<OnceCell<BuddyAllocator>> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut cell = self.lock();
-        let allocator = cell.get_mut().expect("Allocator not initialized");
-
-        // Align size to next power of 2 and respect layout alignment. To
-        // respect layout alignment in the context of buddy allocation, we need
-        // to find the smallest power of 2 that is greater than or equal to the alignment.
-        // This assumes that the heap is aligned to the largest alignment
-        // required by any type. In PathOS, we assume that it is at least 4KiB aligned.
+unsafe impl<A: Allocator> Allocator for Locked<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.lock().allocate(layout)
+    }
 
-        // We still need to align the size to the next power of two,
-        // because default alignment is 1 while the size can be not a power of two.
-        let size = layout.pad_to_align().size().next_power_of_two();
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.lock().deallocate(ptr, layout)
+    }
+}
 
-        // Align size to at least MIN_BLOCK_SIZE.
-        let size = size.max(allocator.min_block_size);
-        // serial_debug!("Allocating {} bytes", size);
+/// Kernel heap: small allocations are served by slab caches, everything else
+/// by the buddy allocator, which also provides the slabs. Tasks have a cache
+/// of their own.
+#[derive(Debug)]
+pub struct KernelHeap {
+    buddy: Heap<MAX_HEAP_REGIONS>,
+    slabs: SlabAllocator,
+    tasks: ObjectCache<Task>,
+}
 
-        let idx = allocator.find_block(size).unwrap();
-        // serial_debug!("Found index: {}", idx);
+impl KernelHeap {
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        // Returning null on exhaustion lets fallible collection methods such
+        // as `try_reserve` fail gracefully, and everything else ends up in
+        // `alloc_error`.
+        if let Some(cache) = self.slabs.cache_for(layout) {
+            return cache
+                .alloc(&mut self.buddy)
+                .map_or(ptr::null_mut(), |addr| addr as *mut u8);
+        }
 
-        let order_start_idx = allocator.order_start_index(size);
+        // serial_debug!("Allocating {} bytes", layout.size());
 
-        // The start address of the block is as far from the start of the heap
-        // as the index is from the first index of the order multiplied by the
-        // size of the block. This assumes that the allocator always returns the
-        // next free block in the order.
-        let block_start_addr = ALLOC_START + (idx - order_start_idx) * size;
+        let Ok(block_start_addr) = self.buddy.alloc_layout(layout) else {
+            return ptr::null_mut();
+        };
 
         // serial_debug!("Found block at adress: {:?}", block_start_addr as *mut u8);
         block_start_addr as *mut u8
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        if let Some(cache) = self.slabs.cache_for(layout) {
+            return cache.dealloc(ptr as usize, &mut self.buddy);
+        }
+
+        // serial_debug!("Deallocating {} bytes at {:?}", layout.size(), ptr);
+
+        self.buddy.dealloc_layout(ptr as usize, layout);
+    }
+
+    // Resizes the memory at `ptr` without moving it if it stays in the same
+    // slab object or the buddy block can grow or shrink in place.
+    fn resize_in_place(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> bool {
+        let old_class = self.slabs.cache_for(layout).map(|c| c.object_size());
+        let new_class = self.slabs.cache_for(new_layout).map(|c| c.object_size());
+
+        match (old_class, new_class) {
+            (Some(old), Some(new)) => old == new,
+            (None, None) => {
+                let old_size = self.buddy.layout_size(layout).unwrap();
+                let Some(new_size) = self.buddy.layout_size(new_layout) else {
+                    return false;
+                };
+                self.buddy
+                    .resize_in_place(ptr as usize, old_size, new_size, layout.align())
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "debug-alloc")]
+impl KernelHeap {
+    fn alloc_guarded(&mut self, layout: Layout) -> *mut u8 {
+        let Some(guarded) = Guarded::new(layout) else {
+            return ptr::null_mut();
+        };
+
+        let outer = self.alloc(guarded.outer);
+        if outer.is_null() {
+            return outer;
+        }
+
+        unsafe { guarded.arm(outer as usize) as *mut u8 }
+    }
+
+    unsafe fn dealloc_guarded(&mut self, ptr: *mut u8, layout: Layout) {
+        let guarded = Guarded::new(layout).unwrap();
+        let outer = guarded.outer(ptr as usize);
+
+        // A block that is no longer allocated cannot be read safely, so the
+        // buddy allocator is asked before looking at the red zones.
+        let result = if self.slabs.cache_for(guarded.outer).is_none()
+            && !self
+                .buddy
+                .is_allocated(outer, self.buddy_size(guarded.outer), layout.align())
+        {
+            Err(Corruption::DoubleFree)
+        } else {
+            guarded.check(ptr as usize)
+        };
+
+        if let Err(corruption) = result {
+            self.report_corruption(ptr, guarded, corruption);
+        }
+
+        guarded.poison(ptr as usize);
+        self.dealloc(outer as *mut u8, guarded.outer);
+    }
+
+    fn buddy_size(&self, layout: Layout) -> usize {
+        self.buddy.layout_size(layout).unwrap()
+    }
+
+    fn report_corruption(&mut self, ptr: *mut u8, guarded: Guarded, corruption: Corruption) -> ! {
+        let outer = guarded.outer(ptr as usize);
+        let block = match self.slabs.cache_for(guarded.outer) {
+            Some(cache) => self.buddy.block_index(outer, cache.slab_size(), 1),
+            None => self.buddy.block_index(
+                outer,
+                self.buddy_size(guarded.outer),
+                guarded.layout.align(),
+            ),
+        };
+
+        crate::serial_error!(" ");
+        crate::serial_error!("*** HEAP CORRUPTION ***");
+        crate::serial_error!(" ");
+        crate::serial_error!(
+            "{} ::: ptr: {:?}, size: {}, align: {}, block: {}",
+            corruption,
+            ptr,
+            guarded.layout.size(),
+            guarded.layout.align(),
+            block
+        );
+
+        panic!("Heap corruption");
+    }
+}
+
+// Serves allocations from the start of the heap until `init_allocator` hands
+// the heap over to the buddy allocator, e.g. everything done in `kinit`.
+fn boot_alloc(layout: Layout) -> *mut u8 {
+    let mut cell = BOOT_ALLOCATOR.lock();
+    cell.get_or_init(|| unsafe { BumpAllocator::new(HEAP_START, BOOT_HEAP_SIZE) });
+    let boot = cell.get_mut().unwrap();
+
+    boot.alloc(layout)
+        .map_or(ptr::null_mut(), |addr| addr as *mut u8)
+}
+
+fn is_boot_allocation(ptr: *mut u8) -> bool {
+    let cell = BOOT_ALLOCATOR.lock();
+    cell.get().is_some_and(|boot| boot.contains(ptr as usize))
+}
+
+unsafe impl GlobalAlloc for Locked<OnceCell<KernelHeap>> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut cell = self.lock();
-        let allocator = cell.get_mut().expect("Allocator not initialized");
+        let Some(heap) = cell.get_mut() else {
+            return boot_alloc(layout);
+        };
+
+        #[cfg(feature = "debug-alloc")]
+        let ptr = heap.alloc_guarded(layout);
+        #[cfg(not(feature = "debug-alloc"))]
+        let ptr = heap.alloc(layout);
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Boot allocations are never freed, the heap keeps them reserved
+        if is_boot_allocation(ptr) {
+            return;
+        }
 
-        let size = layout.pad_to_align().size().next_power_of_two();
-        // Align size to at least MIN_BLOCK_SIZE. This is required because e.g.
-        // a request to free 1 byte will have layout size 1 (with default
-        // alignment = 1).
-        let size = size.max(allocator.min_block_size);
+        let mut cell = self.lock();
+        let heap = cell.get_mut().expect("Allocator not initialized");
 
-        // serial_debug!("Deallocating {} bytes at {:?}", size, ptr);
+        #[cfg(feature = "debug-alloc")]
+        heap.dealloc_guarded(ptr, layout);
+        #[cfg(not(feature = "debug-alloc"))]
+        heap.dealloc(ptr, layout);
+    }
 
-        let order_start_idx = allocator.order_start_index(size);
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
 
-        // To find the index of the block, we reverse the formula used in the
-        // alloc function.
-        // index - order_start_idx = (block_start - heap_start) / size
-        // index = order_start_idx + (block_start - heap_start) / size
-        let idx = order_start_idx + (ptr as usize - ALLOC_START) / size;
-        // serial_debug!("Calculated index: {}", idx);
+        // Red zones move with the size, so debug allocations are always copied
+        if !cfg!(feature = "debug-alloc") && !is_boot_allocation(ptr) {
+            let mut cell = self.lock();
+            if let Some(heap) = cell.get_mut() {
+                if heap.resize_in_place(ptr, layout, new_layout) {
+                    return ptr;
+                }
+            }
+        }
 
-        allocator.free_block(idx);
-        // serial_debug!("Deallocated block at index: {}", idx);
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
 
+#[cfg(all(not(test), target_os = "none"))]
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    crate::serial_error!(" ");
+    crate::serial_error!("*** OUT OF MEMORY ***");
+    crate::serial_error!(" ");
+    crate::serial_error!("Failed to allocate {:?}", layout);
+
+    crate::debug::dump_heap_stats();
+
+    panic!("Out of memory");
+}
+
 #[global_allocator]
-static ALLOCATOR: Locked<OnceCell<BuddyAllocator>> = Locked::new(OnceCell::new());
+static ALLOCATOR: Locked<OnceCell<KernelHeap>> = Locked::new(OnceCell::new());
+
+static BOOT_ALLOCATOR: Locked<OnceCell<BumpAllocator>> = Locked::new(OnceCell::new());
 
 pub fn init_allocator() {
     let allocator = ALLOCATOR.lock();
+    let mut heap = Heap::new(MIN_BLOCK_SIZE);
+
+    let boot_used = BOOT_ALLOCATOR.lock().get().map_or(0, |boot| boot.used());
+    crate::serial_debug!("Boot allocator used {} bytes", boot_used);
+
     unsafe {
-        allocator
-            .set(BuddyAllocator::new(HEAP_START, HEAP_SIZE, MIN_BLOCK_SIZE))
-            .expect("Allocator already initialized");
+        heap.add_region_in_use(HEAP_START, HEAP_SIZE, boot_used)
+            .expect("Failed to add kernel heap memory to the heap");
+
+        // The rest of RAM is not used by the linker script. The region in
+        // between belongs to the frame allocator. Neither is part of the
+        // kernel image, so both are reached through the direct map.
+        let rest = ALLOC_START + ALLOC_SIZE;
+        heap.add_region(
+            phys_to_virt(Paddr::new(rest as u64)).inner() as usize,
+            MEMORY_END - rest,
+        )
+        .expect("Failed to add remaining memory to the heap");
+    }
+
+    let heap = KernelHeap {
+        buddy: heap,
+        slabs: SlabAllocator::new(),
+        tasks: ObjectCache::new(),
+    };
+
+    allocator.set(heap).expect("Allocator already initialized");
+}
+
+pub fn heap_stats() -> Stats {
+    let cell = ALLOCATOR.lock();
+    let heap = cell.get().expect("Allocator not initialized");
+    heap.buddy.stats()
+}
+
+/// Allocator of boxed tasks, served by the task cache of the kernel heap
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaskAllocator;
+
+unsafe impl Allocator for TaskAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        assert_eq!(layout, Layout::new::<Task>(), "Not a task layout");
+
+        let mut cell = ALLOCATOR.lock();
+        let heap = cell.get_mut().ok_or(AllocError)?;
+        let task = heap.tasks.alloc(&mut heap.buddy).ok_or(AllocError)?;
+
+        Ok(NonNull::slice_from_raw_parts(task.cast(), layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        let mut cell = ALLOCATOR.lock();
+        let heap = cell.get_mut().expect("Allocator not initialized");
+        heap.tasks.dealloc(ptr.cast(), &mut heap.buddy);
+    }
+}
+
+/// Adds `start..start + size` to the kernel heap.
+///
+/// # Safety
+///
+/// The memory must not be used for anything else and must be mapped wherever
+/// the heap is used.
+pub unsafe fn add_heap_region(start: usize, size: usize) -> Result<(), &'static str> {
+    let mut cell = ALLOCATOR.lock();
+    let heap = cell.get_mut().expect("Allocator not initialized");
+    heap.buddy.add_region(start, size)
+}
+
+/// Private heap of a subsystem, carved out of the kernel heap. Allocations
+/// made through it with `Vec::new_in(&arena)` and friends are accounted
+/// separately, and all of its memory goes back to the kernel heap at once
+/// when it is dropped.
+pub struct Arena {
+    arena: Locked<arena::Arena>,
+    start: usize,
+    layout: Layout,
+}
+
+impl Arena {
+    pub fn new(size: usize) -> Result<Self, &'static str> {
+        let layout = Layout::from_size_align(size, PAGE_SIZE).map_err(|_| "Invalid arena size")?;
+
+        let start = unsafe { ALLOCATOR.alloc(layout) } as usize;
+        if start == 0 {
+            return Err("Not enough memory for arena");
+        }
+
+        match unsafe { arena::Arena::new(start, size, MIN_BLOCK_SIZE) } {
+            Ok(arena) => Ok(Self {
+                arena: Locked::new(arena),
+                start,
+                layout,
+            }),
+            Err(err) => {
+                unsafe { ALLOCATOR.dealloc(start as *mut u8, layout) };
+                Err(err)
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.arena.lock().stats()
+    }
+}
+
+unsafe impl Allocator for Arena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.arena.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.arena.deallocate(ptr, layout)
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe { ALLOCATOR.dealloc(self.start as *mut u8, self.layout) };
     }
 }
@@ -1,24 +1,100 @@
     .option  norvc
 
-    .section .text.boot
+# Runs in M-mode at the physical address the kernel was loaded at. Prepares
+# the hart for the kernel, which runs in S-mode in the upper half, and enters
+# it through the boot page table.
+    .section .text.boot, "ax"
     .global  _start
 _start:
     csrw     satp, zero                # Disable paging
 
-    la       a0, _bss_start            # Initialize BSS section to zero
-    la       a1, _bss_end
-    bgeu     a0, a1, 2f
+    la       t0, 3f                    # M-mode traps are not expected
+    csrw     mtvec, t0
 
-1:
-    sd       zero, (a0)
-    addi     a0, a0, 8
-    bltu     a0, a1, 1b
+    li       t0, -1                    # Let S-mode access all physical memory
+    csrw     pmpaddr0, t0
+    li       t0, 0x1f
+    csrw     pmpcfg0, t0
+
+    li       t0, 10                    # Probe the largest paging mode, from
+    li       t2, 0xffff                # Sv57 down to Sv39, together with the
+    slli     t2, t2, 44                # ASID bits it implements. Writes of
+1:                                     # unsupported modes are ignored.
+    slli     t1, t0, 60
+    or       t1, t1, t2
+    csrw     satp, t1
+    csrr     a0, satp
+    srli     t1, a0, 60
+    beq      t1, t0, 2f
+    addi     t0, t0, -1
+    li       t1, 8
+    bgeu     t0, t1, 1b
+    j        3f                        # Without paging, there is no kernel
 
 2:
-    la       sp, _stack_end            # Prepare to switch to Rust-based entry code
+    csrw     satp, zero
 
-    csrwi    pmpcfg0, 0xf              # Let S-mode access all physical memory
-    li       t0, 0xffffffffffffff >> 2
-    csrw     pmpaddr0, t0
+    li       t0, 0xb1ff                # Delegate all exceptions apart from
+    csrw     medeleg, t0               # S-mode and M-mode ecalls
+    li       t0, 0x222                 # and all S-mode interrupts
+    csrw     mideleg, t0
+
+    li       t0, 1                     # Let S-mode program its timer (Sstc)
+    slli     t0, t0, 63
+    csrs     0x30a, t0                 # menvcfg
+    csrr     a1, 0x30a
+    srli     a1, a1, 63
+    li       t0, 1 << 1                # and read the time
+    csrw     mcounteren, t0
+
+    la       t0, boot_page_table
+    srli     t0, t0, 12
+    li       t1, 8 << 60               # Sv39
+    or       t0, t0, t1
+    csrw     satp, t0
+
+    li       t0, (1 << 11) | (1 << 13) # Return to S-mode with interrupts off
+    csrw     mstatus, t0
+    ld       t0, kernel_entry
+    csrw     mepc, t0
+    mret                               # kinit(probed satp, Sstc support)
 
+    .balign  4
+3:
+    wfi
+    j        3b
+
+    .section .rodata.boot, "a"
+    .balign  8
+kernel_entry:
+    .dword   _start_kernel
+
+# Sv39 table that maps the first GiB of RAM at the start of the kernel half
+# and the first 4 GiB of physical memory in the direct map, with global
+# gigapages. The kernel switches to its own tables once it has built them.
+    .balign  4096
+boot_page_table:
+    .zero    256 * 8
+    .dword   (0x80000000 >> 2) | 0xef  # 0xffff_ffc0_0000_0000, VRWXGAD
+    .zero    127 * 8
+    .dword   (0x00000000 >> 2) | 0xe7  # 0xffff_ffe0_0000_0000, VRWGAD
+    .dword   (0x40000000 >> 2) | 0xe7
+    .dword   (0x80000000 >> 2) | 0xe7
+    .dword   (0xc0000000 >> 2) | 0xe7
+    .zero    124 * 8
+
+# First code in the upper half, in S-mode on the boot page table
+    .section .text
+_start_kernel:
+    la       t0, _bss_start            # Initialize BSS section to zero
+    la       t1, _bss_end
+    bgeu     t0, t1, 2f
+
+1:
+    sd       zero, (t0)
+    addi     t0, t0, 8
+    bltu     t0, t1, 1b
+
+2:
+    la       sp, _stack_end            # Prepare to switch to Rust-based entry code
     call     kinit
@@ -8,6 +8,10 @@ HEAP_START:
 HEAP_SIZE:
     .dword   _heap_size
 
+    .global  BOOT_HEAP_SIZE
+BOOT_HEAP_SIZE:
+    .dword   _boot_heap_size
+
 # .global HEAP_END
 # HEAP_END:
 # .dword _heap_end
@@ -60,6 +64,18 @@ KERNEL_STACK_START:
 KERNEL_STACK_END:
     .dword   _stack_end
 
+    .global  TRAP_STACK_START
+TRAP_STACK_START:
+    .dword   _trap_stack_start
+
+    .global  TRAP_STACK_END
+TRAP_STACK_END:
+    .dword   _trap_stack_end
+
+    .global  KERNEL_OFFSET
+KERNEL_OFFSET:
+    .dword   _kernel_offset
+
     .global  MEMORY_START
 MEMORY_START:
     .dword   _memory_start
@@ -1 +1,3 @@
 pub const TASK_BEGIN_VADDR: u64 = 0x20_0000_0000;
+/// Where memory mapped for tasks without a requested address starts
+pub const MMAP_BEGIN_VADDR: u64 = 0x30_0000_0000;
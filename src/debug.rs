@@ -1,20 +1,6 @@
-use crate::SCHEDULER;
-
-#[inline(always)]
-pub fn dump_machine_registers() {
-    let mip = hal_riscv::cpu::read_mip();
-    let mie = hal_riscv::cpu::read_mie();
-    let mstatus = hal_riscv::cpu::read_mstatus();
-    let mtval = hal_riscv::cpu::read_mtval();
-    let mepc = hal_riscv::cpu::read_mepc();
+use hal_core::page::PageTable;
 
-    crate::serial_debug!("{}", mstatus);
-    crate::serial_debug!("{}", mie);
-    crate::serial_debug!("{}", mip);
-    crate::serial_debug!("mepc ::: {:?}", mepc);
-    crate::serial_debug!("mtval ::: {:?}", mtval);
-    crate::serial_debug!("sp ::: {:?}", hal_riscv::cpu::read_sp());
-}
+use crate::SCHEDULER;
 
 #[inline(always)]
 pub unsafe fn dump_trap_frame() {
@@ -37,4 +23,34 @@ pub fn dump_supervisor_registers() {
     crate::serial_debug!("{}", sip);
     crate::serial_debug!("sepc ::: {:?}", sepc);
     crate::serial_debug!("stval ::: {:?}", stval);
+    crate::serial_debug!("sp ::: {:?}", hal_riscv::cpu::read_sp());
+}
+
+#[inline(always)]
+pub fn dump_heap_stats() {
+    let stats = crate::alloc::heap_stats();
+
+    crate::serial_debug!("{}", stats);
+    for (order, count) in stats.free_blocks.iter().enumerate() {
+        if *count > 0 {
+            crate::serial_debug!(
+                "free blocks ::: {} B x {}",
+                stats.min_block_size << order,
+                count
+            );
+        }
+    }
+}
+
+/// Prints every mapping below `root` in the style of `/proc/self/maps`.
+pub fn dump_page_tables(root: &PageTable) {
+    crate::serial_debug!(
+        "{:<33} {:<5} {:<16} level rsw",
+        "virtual",
+        "flags",
+        "physical"
+    );
+    for mapping in crate::page::mappings(root) {
+        crate::serial_debug!("{}", mapping);
+    }
 }
@@ -1,34 +1,156 @@
-use core::arch::asm;
+use hal_core::page::{EntryFlags, PAGE_SIZE};
 
-#[repr(u8)]
+use crate::page::{self, MapError};
+
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// Calls of user programs, which pass the number in t5 and the arguments in
+/// a0-a2. The result is returned in a0, errors as their negated code.
 #[derive(Debug)]
-pub enum Ecall {
-    SModeFinishBootstrap,
-    ClearPendingInterrupt(u8),
-    Exit(u8),
+pub enum UserEcall {
+    /// Maps `len` bytes of zeroed memory at `addr`, or wherever there is
+    /// room if it is zero, and returns the address
+    Mmap {
+        addr: usize,
+        len: usize,
+        prot: u64,
+    },
+    Munmap {
+        addr: usize,
+        len: usize,
+    },
+    Mprotect {
+        addr: usize,
+        len: usize,
+        prot: u64,
+    },
+    /// Opens the shared memory object named `id`, creating it with `len`
+    /// bytes if there is none, and returns its size
+    ShmOpen {
+        id: usize,
+        len: usize,
+    },
+    /// Maps all of the open object `id` at `addr`, or wherever there is room
+    /// if it is zero, and returns the address
+    ShmMap {
+        id: usize,
+        addr: usize,
+        prot: u64,
+    },
+    /// Removes the shared mapping at `addr`
+    ShmUnmap {
+        addr: usize,
+    },
+    /// Closes the object `id`, which lives on while it is mapped or open
+    /// elsewhere
+    ShmClose {
+        id: usize,
+    },
+}
+
+pub fn read_user_ecall(ecall: u64, args: [u64; 3]) -> Option<UserEcall> {
+    let [a0, a1, a2] = args.map(|arg| arg as usize);
+
+    match ecall {
+        4 => Some(UserEcall::Mmap {
+            addr: a0,
+            len: a1,
+            prot: a2 as u64,
+        }),
+        5 => Some(UserEcall::Munmap { addr: a0, len: a1 }),
+        6 => Some(UserEcall::Mprotect {
+            addr: a0,
+            len: a1,
+            prot: a2 as u64,
+        }),
+        7 => Some(UserEcall::ShmOpen { id: a0, len: a1 }),
+        8 => Some(UserEcall::ShmMap {
+            id: a0,
+            addr: a1,
+            prot: a2 as u64,
+        }),
+        9 => Some(UserEcall::ShmUnmap { addr: a0 }),
+        10 => Some(UserEcall::ShmClose { id: a0 }),
+        _ => None,
+    }
+}
+
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcallError {
+    InvalidArgument = 1,
+    OutOfMemory = 2,
+    NotMapped = 3,
+    /// The range is already in use
+    Overlap = 4,
+    /// No free range of user addresses is large enough
+    NoSpace = 5,
+    /// The task has no shared memory object open with the name
+    NotOpen = 6,
 }
 
-pub fn ecall(call: Ecall) {
-    match call {
-        Ecall::SModeFinishBootstrap => unsafe { asm!("ecall", in("x30") 1) },
-        Ecall::ClearPendingInterrupt(cause) => unsafe {
-            asm!("ecall", in("x30") 2, in("x31") cause)
-        },
-        Ecall::Exit(code) => unsafe { asm!("ecall", in("x30") 3, in("x31") code) },
+impl From<MapError> for EcallError {
+    fn from(err: MapError) -> Self {
+        match err {
+            MapError::OutOfFrames => EcallError::OutOfMemory,
+            MapError::NotMapped => EcallError::NotMapped,
+            MapError::AlreadyMapped | MapError::Overlap => EcallError::Overlap,
+            MapError::NoSpace => EcallError::NoSpace,
+        }
     }
 }
 
-pub fn read_ecall() -> Ecall {
-    let ecall: u8;
-    let payload: u8;
-    unsafe {
-        asm!("", out("x30") ecall, out("x31") payload);
+/// Value returned to the user program in a0
+pub fn encode_result(result: Result<usize, EcallError>) -> u64 {
+    match result {
+        Ok(value) => value as u64,
+        Err(err) => (err as u64).wrapping_neg(),
     }
+}
 
-    match ecall {
-        1 => Ecall::SModeFinishBootstrap,
-        2 => Ecall::ClearPendingInterrupt(payload),
-        3 => Ecall::Exit(payload),
-        _ => panic!("Unknown ecall: {}", ecall),
+/// Page aligned range of `len` bytes at `addr`, rounded up to whole pages.
+/// Fails unless it is non-empty and lies in the user half.
+pub fn user_range(addr: usize, len: usize) -> Result<(usize, usize), EcallError> {
+    let end = len
+        .checked_next_multiple_of(PAGE_SIZE as usize)
+        .and_then(|len| addr.checked_add(len))
+        .filter(|&end| end > addr && page::is_user_address(end - 1))
+        .ok_or(EcallError::InvalidArgument)?;
+
+    if !addr.is_multiple_of(PAGE_SIZE as usize) {
+        return Err(EcallError::InvalidArgument);
     }
+
+    Ok((addr, end))
+}
+
+/// `len` rounded up to whole pages. Fails unless it is non-zero and fits in
+/// the user half.
+pub fn user_len(len: usize) -> Result<usize, EcallError> {
+    len.checked_next_multiple_of(PAGE_SIZE as usize)
+        .filter(|&len| len > 0 && len as u64 <= page::paging_mode().user_end())
+        .ok_or(EcallError::InvalidArgument)
+}
+
+/// Leaf flags for the `PROT_*` bits in `prot`. Writable pages must be
+/// readable on RISC-V, and pages without any access are not supported.
+pub fn prot_flags(prot: u64) -> Result<EntryFlags, EcallError> {
+    if prot == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(EcallError::InvalidArgument);
+    }
+
+    let mut flags = EntryFlags::USER;
+    if prot & (PROT_READ | PROT_WRITE) != 0 {
+        flags |= EntryFlags::READ;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags |= EntryFlags::WRITE;
+    }
+    if prot & PROT_EXEC != 0 {
+        flags |= EntryFlags::EXECUTE;
+    }
+
+    Ok(flags)
 }
@@ -0,0 +1,141 @@
+extern crate alloc;
+
+use alloc::vec;
+use allocator::buddy::BuddyAllocator;
+use core::ptr;
+use hal_core::page::{Frame, FrameAllocator, Paddr};
+use once_cell::unsync::OnceCell;
+
+use crate::alloc::Locked;
+use crate::page::phys_to_virt;
+use crate::{ALLOC_SIZE, ALLOC_START};
+
+const FRAME_SIZE: usize = 4096;
+
+/// Hands out the 4KiB frames of a physical memory region. The bookkeeping
+/// lives on the kernel heap, so the whole region is available for frames.
+#[derive(Debug)]
+pub struct PhysicalFrameAllocator {
+    start: usize,
+    frames: BuddyAllocator,
+    refcounts: &'static mut [u16],
+}
+
+impl PhysicalFrameAllocator {
+    /// # Safety
+    ///
+    /// `start..start + size` must be unused physical memory in the direct map.
+    pub unsafe fn new(start: usize, size: usize) -> Self {
+        let bookkeeping = vec![0u8; BuddyAllocator::bookkeeping_size(size, FRAME_SIZE)].leak();
+        let refcounts = vec![0u16; size / FRAME_SIZE].leak();
+
+        Self {
+            start,
+            frames: BuddyAllocator::new(bookkeeping.as_mut_ptr() as usize, size, FRAME_SIZE),
+            refcounts,
+        }
+    }
+
+    fn index(&self, frame: Frame) -> usize {
+        let addr = frame.addr().inner() as usize;
+        assert!(
+            (self.start..self.start + self.refcounts.len() * FRAME_SIZE).contains(&addr),
+            "Frame 0x{:x} does not belong to the frame allocator",
+            addr
+        );
+
+        (addr - self.start) / FRAME_SIZE
+    }
+}
+
+impl FrameAllocator for PhysicalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        let idx = self.frames.find_block(FRAME_SIZE).ok()?;
+        let addr = self.start + self.frames.block_offset(idx);
+
+        let vaddr = phys_to_virt(Paddr::new(addr as u64));
+        unsafe { ptr::write_bytes(vaddr.as_mut_ptr::<u8>(), 0, FRAME_SIZE) };
+
+        let frame = Frame::containing_address(addr as u64);
+        let index = self.index(frame);
+        self.refcounts[index] = 1;
+
+        Some(frame)
+    }
+
+    fn retain_frame(&mut self, frame: Frame) {
+        let index = self.index(frame);
+        assert_ne!(
+            self.refcounts[index], 0,
+            "Frame {:?} is not allocated",
+            frame
+        );
+
+        self.refcounts[index] = self.refcounts[index]
+            .checked_add(1)
+            .expect("Too many references to frame");
+    }
+
+    fn release_frame(&mut self, frame: Frame) -> bool {
+        let index = self.index(frame);
+        assert_ne!(
+            self.refcounts[index], 0,
+            "Frame {:?} released more often than retained",
+            frame
+        );
+
+        self.refcounts[index] -= 1;
+        if self.refcounts[index] > 0 {
+            return false;
+        }
+
+        let idx = self.frames.block_index(index * FRAME_SIZE, FRAME_SIZE);
+        self.frames.free_block(idx);
+        true
+    }
+
+    fn refcount(&self, frame: Frame) -> u16 {
+        self.refcounts[self.index(frame)]
+    }
+}
+
+static FRAME_ALLOCATOR: Locked<OnceCell<PhysicalFrameAllocator>> = Locked::new(OnceCell::new());
+
+pub fn init_frame_allocator() {
+    let allocator = FRAME_ALLOCATOR.lock();
+
+    unsafe {
+        allocator
+            .set(PhysicalFrameAllocator::new(ALLOC_START, ALLOC_SIZE))
+            .expect("Frame allocator already initialized");
+    }
+}
+
+pub fn try_alloc_frame() -> Option<Frame> {
+    let mut cell = FRAME_ALLOCATOR.lock();
+    let allocator = cell.get_mut().expect("Frame allocator not initialized");
+    allocator.allocate_frame()
+}
+
+pub fn alloc_frame() -> Frame {
+    try_alloc_frame().expect("Out of physical frames")
+}
+
+pub fn retain_frame(frame: Frame) {
+    let mut cell = FRAME_ALLOCATOR.lock();
+    let allocator = cell.get_mut().expect("Frame allocator not initialized");
+    allocator.retain_frame(frame)
+}
+
+/// Number of references to an allocated frame
+pub fn frame_refcount(frame: Frame) -> u16 {
+    let cell = FRAME_ALLOCATOR.lock();
+    let allocator = cell.get().expect("Frame allocator not initialized");
+    allocator.refcount(frame)
+}
+
+pub fn release_frame(frame: Frame) -> bool {
+    let mut cell = FRAME_ALLOCATOR.lock();
+    let allocator = cell.get_mut().expect("Frame allocator not initialized");
+    allocator.release_frame(frame)
+}
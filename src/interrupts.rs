@@ -1,63 +1,102 @@
 extern crate alloc;
 
+use crate::address_space::Access;
 use crate::constants::TASK_BEGIN_VADDR;
-use crate::debug::dump_machine_registers;
-use crate::ecall::{self, Ecall};
+use crate::debug::dump_supervisor_registers;
+use crate::ecall::{self, EcallError, UserEcall};
 use crate::serial::write_empty_line;
-use crate::trap::{restore_cpu_registers, save_cpu_registers, TrapFrame};
-use crate::{serial_debug, SCHEDULER};
+use crate::shm::{self, ShmId};
+use crate::stack;
+use crate::trap::{restore_cpu_registers, save_cpu_registers, Task, TrapFrame};
+use crate::{serial_debug, serial_error, serial_info, SCHEDULER};
 
 use core::arch::asm;
 use core::panic;
-use hal_riscv::cpu::{self, read_mstatus, Cause, Exception, Interrupt, Mie, Mstatus};
+use hal_riscv::cpu::{self, read_sstatus, Cause, Exception, Interrupt, Sie, Sstatus};
+
+/// Time slice of a task, in ticks of the time CSR
+const TIME_SLICE: u64 = 10_000_000;
 
 #[inline(always)]
-pub fn init_m_mode_ivt() {
-    hal_riscv::cpu::write_mtvec(interrupt_handler_naked);
+pub fn init_s_mode_ivt() {
+    hal_riscv::cpu::write_stvec(interrupt_handler_naked);
 }
 
-#[inline(always)]
-fn handle_mti() {
-    let mut mtime = hal_riscv::timer::read_mtime();
-    mtime += 10_000_000;
-    hal_riscv::timer::write_mtimecmp(mtime);
+// Raises the next timer interrupt at the end of a time slice from now
+fn set_timer() {
+    hal_riscv::timer::write_stimecmp(hal_riscv::timer::read_time() + TIME_SLICE);
+}
 
-    let mstatus = read_mstatus();
-    let mstatus = Mstatus { mpp: 0, ..mstatus };
-    cpu::write_mstatus(mstatus);
+#[inline(always)]
+fn handle_sti() {
+    set_timer();
 
-    let mepc = cpu::read_mepc() as u64;
+    let sepc = cpu::read_sepc() as u64;
 
-    schedule_task(UserspaceState::Running(mepc));
+    schedule_task(UserspaceState::Running(sepc));
 }
 
 enum UserspaceState {
     Running(u64),
     Pending,
+    /// The current task continues where it trapped
+    Resumed,
+    /// The current task was terminated
+    Terminated,
 }
 
 #[inline(always)]
 fn schedule_task(state: UserspaceState) {
-    let (next_tid, next_mepc) = match state {
-        UserspaceState::Pending => (0, TASK_BEGIN_VADDR),
-        UserspaceState::Running(mepc) => {
-            let mut cell = SCHEDULER.lock();
-            let scheduler = cell.get_mut().expect("Scheduler not initialized");
-            scheduler.save_state(mepc);
-            let (tid, task) = scheduler.next();
-            (tid, task.pc.inner())
+    let next = {
+        let mut cell = SCHEDULER.lock();
+        let scheduler = cell.get_mut().expect("Scheduler not initialized");
+        let resumed = matches!(state, UserspaceState::Resumed);
+        let next = match state {
+            UserspaceState::Pending => Some((0, TASK_BEGIN_VADDR)),
+            UserspaceState::Resumed => Some((scheduler.current(), cpu::read_sepc() as u64)),
+            UserspaceState::Running(sepc) => {
+                scheduler.save_state(sepc);
+                scheduler.next().map(|(tid, task)| (tid, task.pc.inner()))
+            }
+            UserspaceState::Terminated => {
+                scheduler.next().map(|(tid, task)| (tid, task.pc.inner()))
+            }
+        };
+
+        // A resumed task is still installed
+        if let Some((tid, _)) = next.filter(|_| !resumed) {
+            let task = scheduler.task_mut(tid);
+            task.address_space.activate();
+            stack::set_current(tid, &task.kernel_stack);
         }
+        next
     };
 
-    cpu::write_mepc(next_mepc as *const ());
+    let Some((next_tid, next_sepc)) = next else {
+        serial_info!("All tasks terminated");
+        loop {
+            unsafe { asm!("wfi") }
+        }
+    };
+
+    // Return to U-mode with interrupts enabled
+    cpu::write_sepc(next_sepc as *const ());
+    cpu::clear_sstatus(Sstatus {
+        spp: 1,
+        ..Default::default()
+    });
+    cpu::set_sstatus(Sstatus {
+        spie: 1,
+        ..Default::default()
+    });
 
     unsafe {
         asm!(
             "jal {restore_cpu_registers}",
-            "csrw mscratch, a0",
+            "csrw sscratch, a0",
             "ld ra, 0(a0)",
             "ld a0, 168(a0)",
-            "mret",
+            "sret",
             in("a0") get_task_frame_ptr(next_tid),
             restore_cpu_registers = sym restore_cpu_registers
         )
@@ -65,35 +104,158 @@ fn schedule_task(state: UserspaceState) {
 }
 
 #[no_mangle]
-fn dispatch_machine_exception(mcause: Cause) {
-    match mcause {
-        Cause::Exception(Exception::SupervisorEcall) => {
-            // dump_machine_registers();
-            let ecall = ecall::read_ecall();
-            match ecall {
-                Ecall::SModeFinishBootstrap => handle_smode_finish_bootstrap(),
-                _ => {
-                    panic!("Unimplemented S-mode ecall handler ::: {:?}", mcause)
-                }
-            }
-        }
-        Cause::Exception(Exception::UserEcall) => {
-            dump_machine_registers();
-            serial_debug!("{:?} ::: {:?}", Exception::UserEcall, mcause);
-            schedule_task(UserspaceState::Pending)
-        }
+fn dispatch_supervisor_exception(scause: Cause) {
+    match scause {
+        Cause::Exception(Exception::UserEcall) => handle_user_ecall(&scause),
+        Cause::Exception(
+            ref exc @ (Exception::LoadPageFault
+            | Exception::StorePageFault
+            | Exception::InstructionPageFault),
+        ) if read_sstatus().spp == 0 => handle_user_page_fault(exc),
         Cause::Exception(ref exc) => {
-            dump_machine_registers();
-            serial_debug!("{:?} ::: {:?}", exc, mcause);
+            dump_supervisor_registers();
+            serial_debug!("{:?} ::: {:?}", exc, scause);
             loop {}
         }
         _ => {
-            dump_machine_registers();
-            panic!("Unimplemented M-mode exception ::: {:?}", mcause)
+            dump_supervisor_registers();
+            panic!("Unimplemented S-mode exception ::: {:?}", scause)
+        }
+    }
+
+    unsafe { asm!("sret", clobber_abi("system")) }
+}
+
+// Maps the faulting page if it belongs to one of the task's areas, and
+// terminates the task otherwise
+fn handle_user_page_fault(exception: &Exception) {
+    let access = match exception {
+        Exception::LoadPageFault => Access::Read,
+        Exception::StorePageFault => Access::Write,
+        _ => Access::Execute,
+    };
+    let vaddr = cpu::read_stval() as usize;
+
+    let mut cell = SCHEDULER.lock();
+    let scheduler = cell.get_mut().expect("Scheduler not initialized");
+    let tid = scheduler.current();
+
+    let result = scheduler
+        .task_mut(tid)
+        .address_space
+        .handle_page_fault(vaddr, access);
+
+    match result {
+        Ok(()) => {
+            drop(cell);
+            schedule_task(UserspaceState::Resumed)
+        }
+        Err(err) => {
+            serial_error!(
+                "Task {} terminated ::: {:?} at {:#x}: {:?}",
+                tid,
+                exception,
+                vaddr,
+                err
+            );
+
+            let address_space = scheduler.terminate(tid);
+            drop(cell);
+            drop(address_space);
+            schedule_task(UserspaceState::Terminated)
         }
     }
+}
+
+// Runs memory and shared memory calls of the current task and resumes it
+// after the ecall. Other calls restart the first task.
+fn handle_user_ecall(scause: &Cause) {
+    let mut cell = SCHEDULER.lock();
+    let scheduler = cell.get_mut().expect("Scheduler not initialized");
+    let tid = scheduler.current();
+    let task = scheduler.task_mut(tid);
+
+    let (number, args) = task.trap_frame.user_ecall();
+    let Some(call) = ecall::read_user_ecall(number, args) else {
+        drop(cell);
+        dump_supervisor_registers();
+        serial_debug!("{:?} ::: {:?}", Exception::UserEcall, scause);
+        schedule_task(UserspaceState::Pending);
+        return;
+    };
+
+    let result = run_user_ecall(&call, task);
+    serial_debug!("Task {} ::: {:?} -> {:x?}", tid, call, result);
 
-    unsafe { asm!("mret", clobber_abi("system")) }
+    task.trap_frame
+        .set_return_value(ecall::encode_result(result));
+    drop(cell);
+
+    cpu::write_sepc(cpu::read_sepc().wrapping_byte_add(4));
+    schedule_task(UserspaceState::Resumed)
+}
+
+// Result of `call` that is returned to `task`
+fn run_user_ecall(call: &UserEcall, task: &mut Task) -> Result<usize, EcallError> {
+    let space = &mut task.address_space;
+
+    match *call {
+        UserEcall::Mmap { addr, len, prot } => {
+            let (start, end) = ecall::user_range(addr, len)?;
+            let flags = ecall::prot_flags(prot)?;
+            let start = space.mmap(start, end - start, flags)?;
+            Ok(start)
+        }
+        UserEcall::Munmap { addr, len } => {
+            let (start, end) = ecall::user_range(addr, len)?;
+            space.munmap(start, end)?;
+            Ok(0)
+        }
+        UserEcall::Mprotect { addr, len, prot } => {
+            let (start, end) = ecall::user_range(addr, len)?;
+            let flags = ecall::prot_flags(prot)?;
+            space.mprotect(start, end, flags)?;
+            Ok(0)
+        }
+        UserEcall::ShmOpen { id, len } => {
+            let len = ecall::user_len(len)?;
+            let object = shm::open(ShmId::new(id), len)?;
+            let size = object.size();
+            if !task
+                .shared_memory
+                .iter()
+                .any(|open| open.id() == object.id())
+            {
+                task.shared_memory.push(object);
+            }
+            Ok(size)
+        }
+        UserEcall::ShmMap { id, addr, prot } => {
+            let flags = ecall::prot_flags(prot)?;
+            let object = task
+                .shared_memory
+                .iter()
+                .find(|open| open.id() == ShmId::new(id))
+                .ok_or(EcallError::NotOpen)?;
+            ecall::user_range(addr, object.size())?;
+            let start = space.place(addr, object.size())?;
+            space.map_shared(object, start, flags)?;
+            Ok(start)
+        }
+        UserEcall::ShmUnmap { addr } => {
+            space.unmap_shared(addr)?;
+            Ok(0)
+        }
+        UserEcall::ShmClose { id } => {
+            let index = task
+                .shared_memory
+                .iter()
+                .position(|open| open.id() == ShmId::new(id))
+                .ok_or(EcallError::NotOpen)?;
+            task.shared_memory.swap_remove(index);
+            Ok(0)
+        }
+    }
 }
 
 #[inline(never)]
@@ -103,23 +265,16 @@ fn get_task_frame_ptr(tid: usize) -> *const TrapFrame {
     &scheduler.task(tid).trap_frame as *const _
 }
 
-#[inline(always)]
-fn handle_smode_finish_bootstrap() {
-    let mie = Mie {
-        mtie: 1,
+/// Enables the timer interrupt and runs the first task. Called once the
+/// kernel has finished booting.
+pub fn start_tasks() {
+    let sie = Sie {
+        stie: 1,
         ..Default::default()
     };
 
-    let mstatus = hal_riscv::cpu::read_mstatus();
-    let mstatus = Mstatus {
-        mpp: 0,
-        mpie: 1,
-        fs: 1,
-        ..mstatus
-    };
-
-    hal_riscv::cpu::write_mie(mie.clone());
-    hal_riscv::cpu::write_mstatus(mstatus.clone());
+    hal_riscv::cpu::write_sie(sie);
+    set_timer();
     schedule_task(UserspaceState::Pending)
 }
 
@@ -129,34 +284,62 @@ fn handle_smode_finish_bootstrap() {
 fn interrupt_handler_naked() {
     unsafe {
         asm!(
-            "csrrw a0, mscratch, a0",
-            "beqz a0, {interrupt_handler}",
+            "csrrw a0, sscratch, a0",
+            "beqz a0, 1f",
             "sd ra, 0(a0)",
             "jal {save_cpu_registers}",
+            // Nested traps must not mistake the user's a0 for a trap frame
+            "csrw sscratch, zero",
             "ld sp, 232(a0)",
             "j {interrupt_handler}",
+            // Traps taken in the kernel switch to the trap stack, since the
+            // stack they were taken on may have overflowed
+            "1:",
+            "mv a0, sp",
+            "la sp, _trap_stack_end",
+            "j {handle_kernel_trap}",
             save_cpu_registers = sym save_cpu_registers,
             interrupt_handler = sym interrupt_handler,
+            handle_kernel_trap = sym handle_kernel_trap,
             options(noreturn)
         )
     }
 }
 
+// Runs on the trap stack for traps taken in the kernel, with the stack
+// pointer at the time of the trap
+extern "C" fn handle_kernel_trap(sp: usize) -> ! {
+    let scause = cpu::read_scause();
+    let stval = cpu::read_stval() as usize;
+
+    if let Some(tid) = stack::overflowed_task(stval) {
+        panic!(
+            "Kernel stack overflow in task {} ::: sp {:#x}, {:?} at {:#x}",
+            tid, sp, scause, stval
+        );
+    }
+
+    panic!(
+        "Unexpected kernel trap ::: sp {:#x}, {:?} at {:#x}",
+        sp, scause, stval
+    );
+}
+
 #[inline(always)]
 fn interrupt_handler() {
-    let mcause = cpu::read_mcause();
+    let scause = cpu::read_scause();
 
-    if matches!(mcause, Cause::Exception(_)) {
-        serial_debug!("Machine mode exception cause: {:?}", mcause);
-        dispatch_machine_exception(mcause.clone());
+    if matches!(scause, Cause::Exception(_)) {
+        serial_debug!("Supervisor mode exception cause: {:?}", scause);
+        dispatch_supervisor_exception(scause.clone());
     }
 
-    if matches!(mcause, Cause::Interrupt(Interrupt::MachineTimer)) {
+    if matches!(scause, Cause::Interrupt(Interrupt::SupervisorTimer)) {
         write_empty_line();
-        // serial_debug!("Machine mode interrupt cause: {:?}", mcause);
+        // serial_debug!("Supervisor mode interrupt cause: {:?}", scause);
         // unsafe { dump_trap_frame() }
-        handle_mti();
+        handle_sti();
     }
 
-    unsafe { asm!("mret") }
+    unsafe { asm!("sret") }
 }
@@ -5,21 +5,27 @@
 #![feature(fn_ptr_trait)]
 #![feature(asm_const)]
 #![feature(naked_functions)]
+#![feature(alloc_error_handler)]
+#![feature(allocator_api)]
 
 use core::panic::PanicInfo;
 
-use hal_core::page::{EntryFlags, Page, PageTable, Vaddr};
+use hal_core::page::{EntryFlags, PageTable};
 use page::*;
 use trap::{Scheduler, Task, SCHEDULER};
 
+pub mod address_space;
 pub mod alloc;
 pub mod constants;
 pub mod debug;
 pub mod ecall;
 pub mod elf;
+pub mod frame;
 pub mod interrupts;
 pub mod page;
 pub mod serial;
+pub mod shm;
+pub mod stack;
 pub mod trap;
 
 extern "C" {
@@ -31,118 +37,118 @@ extern "C" {
     pub static BSS_END: usize;
     pub static KERNEL_STACK_START: usize;
     pub static KERNEL_STACK_END: usize;
+    pub static TRAP_STACK_START: usize;
+    pub static TRAP_STACK_END: usize;
     pub static HEAP_START: usize;
     pub static HEAP_SIZE: usize;
+    pub static BOOT_HEAP_SIZE: usize;
     pub static ALLOC_START: usize;
     pub static ALLOC_SIZE: usize;
     pub static MEMORY_START: usize;
     pub static MEMORY_END: usize;
     pub static RODATA_START: usize;
     pub static RODATA_END: usize;
+    pub static KERNEL_OFFSET: usize;
 }
 
 pub const APP_CODE: &[u8] = include_bytes!("app");
 
+// MMIO page of the UART
+const UART_START: usize = 0x1000_0000;
+const UART_END: usize = UART_START + 0x1000;
+
 #[cfg(all(not(test), target_os = "none"))]
 #[panic_handler]
 #[no_mangle]
 fn panic(info: &PanicInfo) -> ! {
-    use debug::{dump_machine_registers, dump_supervisor_registers};
+    use debug::dump_supervisor_registers;
 
     crate::serial_error!(" ");
     crate::serial_error!("*** KERNEL PANIC ***");
     crate::serial_error!(" ");
     crate::serial_error!("{}", info);
 
-    dump_machine_registers();
     dump_supervisor_registers();
 
     loop {}
 }
 
+/// Maps the kernel into `root`, the kernel's own address space. Everything
+/// goes into the upper half, Global, so that task address spaces share it and
+/// leave the lower half to user space.
+///
+/// The image is mapped where it is linked. Every section gets only the
+/// permissions it needs, and the linker script pads them to page boundaries,
+/// so no page is shared by two of them. Physical memory outside the image
+/// and the UART are reached through the direct map window.
 pub unsafe fn init_page_tables(root: &mut PageTable) {
-    // I needed to set .text and .rodata to X because otherwise I
-    // get store page faults on AMO instructions in the kernel
-    // (which probably belong to mutexes or spinlocks).
-
-    id_map_range(root, TEXT_START, TEXT_END, EntryFlags::RWX);
-    serial_debug!(
-        "Identity mapped kernel .text: 0x{:x} - 0x{:x}",
-        TEXT_START,
-        TEXT_END
-    );
-
-    id_map_range(root, RODATA_START, RODATA_END, EntryFlags::RWX);
-    serial_debug!(
-        "Identity mapped kernel .rodata: 0x{:x} - 0x{:x}",
-        RODATA_START,
-        RODATA_END
-    );
-
-    id_map_range(root, DATA_START, DATA_END, EntryFlags::RW);
-    serial_debug!(
-        "Identity mapped kernel .data: 0x{:x} - 0x{:x}",
-        DATA_START,
-        DATA_END
-    );
-
-    id_map_range(root, BSS_START, BSS_END, EntryFlags::RW);
-    serial_debug!(
-        "Identity mapped kernel .bss: 0x{:x} - 0x{:x}",
-        BSS_START,
-        BSS_END
-    );
+    let image = [
+        ("kernel .text", TEXT_START, TEXT_END, EntryFlags::RX),
+        ("kernel .rodata", RODATA_START, RODATA_END, EntryFlags::READ),
+        ("kernel .data", DATA_START, DATA_END, EntryFlags::RW),
+        ("kernel .bss", BSS_START, BSS_END, EntryFlags::RW),
+        (
+            "kernel stack",
+            KERNEL_STACK_START,
+            KERNEL_STACK_END,
+            EntryFlags::RW,
+        ),
+        ("trap stack", TRAP_STACK_START, TRAP_STACK_END, EntryFlags::RW),
+        (
+            "kernel heap",
+            HEAP_START,
+            HEAP_START + HEAP_SIZE,
+            EntryFlags::RW,
+        ),
+    ];
+    let physical = [
+        // Frames, and the memory the heap grows into after them, see
+        // `init_allocator`
+        ("memory", ALLOC_START, MEMORY_END, EntryFlags::RW),
+        ("UART device", UART_START, UART_END, EntryFlags::RW),
+    ];
+
+    for (name, start, end, flags) in image {
+        let (start, end) = (start - KERNEL_OFFSET, end - KERNEL_OFFSET);
+        let flags = flags | EntryFlags::GLOBAL;
+        map_kernel_region(root, name, start, end, KERNEL_OFFSET as u64, flags);
+    }
+    for (name, start, end, flags) in physical {
+        let flags = flags | EntryFlags::GLOBAL;
+        map_kernel_region(root, name, start, end, DIRECT_MAP_OFFSET, flags);
+    }
 
-    id_map_range(root, KERNEL_STACK_START, KERNEL_STACK_END, EntryFlags::RW);
-    serial_debug!(
-        "Identity mapped kernel stack: 0x{:x} - 0x{:x}",
-        KERNEL_STACK_START,
-        KERNEL_STACK_END
-    );
+    if let Err(mapping) = check_wx(root) {
+        panic!("Kernel mapping is writable and executable: {}", mapping);
+    }
+}
 
-    id_map_range(root, HEAP_START, HEAP_START + HEAP_SIZE, EntryFlags::RW);
-    serial_debug!(
-        "Identity mapped kernel heap: 0x{:x} - 0x{:x}",
-        HEAP_START,
-        HEAP_START + HEAP_SIZE
-    );
+// Maps the region `start..end` and performs a sanity check by walking the
+// page tables and making sure it is mapped in full.
+fn map_kernel_region(
+    root: &mut PageTable,
+    name: &str,
+    start: usize,
+    end: usize,
+    offset: u64,
+    flags: EntryFlags,
+) {
+    if start == end {
+        return;
+    }
 
-    id_map_range(root, ALLOC_START, ALLOC_START + ALLOC_SIZE, EntryFlags::RW);
+    offset_map_range(root, start, end - 1, offset, flags);
     serial_debug!(
-        "Identity mapped kernel allocatable memory: 0x{:x} - 0x{:x}",
-        ALLOC_START,
-        ALLOC_START + ALLOC_SIZE
+        "Mapped {}: 0x{:x} - 0x{:x} at 0x{:x}",
+        name,
+        start,
+        end,
+        (start as u64).wrapping_add(offset)
     );
 
-    id_map(root, Page::containing_address(0x10000000), EntryFlags::RW);
-    serial_debug!("Identity mapped UART device: 0x{:x}", 0x10000000);
-
-    // Now perform sanity check by trying to translate each section's start
-    // and end virtual addresses to a physical address.
-    for vaddr in [
-        TEXT_START,
-        TEXT_END,
-        RODATA_START,
-        RODATA_END,
-        DATA_START,
-        DATA_END,
-        BSS_START,
-        BSS_END,
-        KERNEL_STACK_START,
-        KERNEL_STACK_END,
-        // HEAP_START,
-        // HEAP_START + HEAP_SIZE,
-        // ALLOC_START,
-        // ALLOC_START + ALLOC_SIZE,
-    ] {
-        let vaddr = Vaddr::new(vaddr as u64);
-        if translate_vaddr(root, vaddr).is_none() {
-            panic!("0x{:x} cannot be translated", vaddr.inner());
-        }
+    if let Err(vaddr) = check_offset_mapped(root, start, end - 1, offset, flags) {
+        panic!("{} is not mapped at 0x{:x}", name, vaddr.inner());
     }
-
-    // TODO: Check why not every address translation in HEAP and ALLOCATE
-    // sections works.
 }
 
 pub fn init_scheduler(tasks: [Task; 3]) {
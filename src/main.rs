@@ -2,7 +2,6 @@
 #![no_main]
 #![feature(fn_align)]
 #![feature(abi_riscv_interrupt)]
-#![feature(fn_ptr_trait)]
 #![feature(asm_const)]
 
 mod asm;
@@ -10,51 +9,44 @@ mod asm;
 extern crate alloc;
 
 use ::core::arch::asm;
-use ::core::marker::FnPtr;
-use alloc::vec::Vec;
-use hal_core::page::{EntryFlags, Frame, Page, PageTable, Vaddr};
-use hal_riscv::cpu::{Mideleg, Mstatus, Satp, Sstatus};
+use ::core::ptr;
+use hal_core::page::{EntryFlags, Frame, Page, Vaddr, PAGE_SIZE};
+use hal_riscv::cpu::{sfence_vma_all, Satp};
+use pathos::address_space::{self, AddressSpace};
 use pathos::alloc::init_allocator;
 use pathos::constants::TASK_BEGIN_VADDR;
-use pathos::ecall::{ecall, Ecall};
+use pathos::debug::{dump_heap_stats, dump_page_tables};
 use pathos::elf::parse_text;
+use pathos::frame::init_frame_allocator;
 use pathos::trap::Task;
 use pathos::{init_page_tables, init_scheduler, interrupts, page, APP_CODE};
 use pathos::{serial_debug, serial_info, serial_println};
 
 const LOGO: &str = include_str!("logo.txt");
 
+/// Size of the virtual memory of a task, starting at `TASK_BEGIN_VADDR`
+const TASK_MEMORY_SIZE: usize = 1024 * 1024;
+
+/// Entered in S-mode on the boot page table, with the satp value that the boot
+/// code probed and whether the hart supports Sstc
 #[no_mangle]
-pub fn kinit() {
+pub extern "C" fn kinit(satp: u64, sstc: u64) {
     serial_println!("{}", LOGO);
 
-    let mideleg = Mideleg {
-        ..Default::default()
-    };
-
-    // let medeleg = Medeleg { uecall: 1 };
-    let mstatus = Mstatus {
-        mpp: 1,
-        fs: 1,
-        ..Default::default()
-    };
+    let probed = Satp::from_bits(satp);
 
-    hal_riscv::cpu::write_mideleg(mideleg.clone());
-    hal_riscv::cpu::write_mstatus(mstatus.clone());
-    hal_riscv::cpu::write_mepc((main as fn()).addr());
+    let mode = page::init_paging_mode(&probed);
+    serial_info!("Detected {:?} paging support", mode);
 
-    init_scheduler([
-        Task::new(Vaddr::new(0x20_0000_0000), 0),
-        Task::new(Vaddr::new(0x20_0000_0000), 1),
-        Task::new(Vaddr::new(0x20_0000_0000), 2),
-    ]);
+    let asid_bits = address_space::init_asids(&probed);
+    serial_info!("Detected {} ASID bits", asid_bits);
 
-    serial_info!("Initialized task scheduler");
+    assert!(sstc != 0, "Sstc not supported, S-mode cannot program its timer");
 
-    interrupts::init_m_mode_ivt();
-    serial_info!("Initialized machine mode interrupt vector table");
+    interrupts::init_s_mode_ivt();
+    serial_info!("Initialized supervisor mode interrupt vector table");
 
-    unsafe { asm!("mret") }
+    main();
 }
 
 #[no_mangle]
@@ -62,62 +54,81 @@ pub fn main() {
     init_allocator();
     serial_info!("Initialized global heap allocator");
 
-    // Identity map kernel code and data before switching to Sv39 paging
-    let root = page::allocate_root();
+    init_frame_allocator();
+    serial_info!("Initialized physical frame allocator");
+
+    // Map the kernel before switching on paging. The kernel keeps running
+    // in this address space, so it is never dropped.
+    let mut kernel_space = AddressSpace::new();
     unsafe {
-        init_page_tables(root);
+        init_page_tables(kernel_space.root_mut());
     }
+    kernel_space.share_kernel_half();
+    dump_page_tables(kernel_space.root());
+
+    // Every task gets its own address space with the same program
+    let program = parse_text(APP_CODE);
+    init_scheduler(core::array::from_fn(|tid| {
+        let mut space = AddressSpace::new();
+        map_userspace_program(&mut space, program);
+        Task::new(Vaddr::new(TASK_BEGIN_VADDR), tid as u64, space)
+    }));
+    serial_info!("Initialized task scheduler");
 
-    map_userspace_program(root);
-
-    // Create satp entry and enable Sv39 paging
-    let satp = Satp::new(8, root as *mut PageTable as usize);
-    hal_riscv::cpu::write_satp(satp);
-
-    serial_info!("Enabled Sv39 paging");
+    dump_heap_stats();
 
-    let sstatus = Sstatus {
-        spp: 0,
-        ..Default::default()
-    };
+    // The boot page table maps everything global, so its entries are not
+    // flushed by switching to the kernel's ASID
+    kernel_space.activate();
+    sfence_vma_all();
+    serial_info!("Enabled {:?} paging", page::paging_mode());
 
-    hal_riscv::cpu::set_sstatus(sstatus);
-    ecall(Ecall::SModeFinishBootstrap);
+    interrupts::start_tasks();
 
     loop {
         unsafe { asm!("wfi") }
     }
 }
 
-fn map_userspace_program(root: &mut PageTable) {
-    // Parse userspace binary text section and put data somewhere in the heap
-    let program_text_section = parse_text(APP_CODE);
-    let data = Vec::from(program_text_section).leak();
-    serial_debug!("Copied user program to address: {:#x?}", data.as_ptr());
+fn map_userspace_program(space: &mut AddressSpace, program: &[u8]) {
+    let start = TASK_BEGIN_VADDR as usize;
+    let end = start + TASK_MEMORY_SIZE;
 
-    let pstart = data.as_ptr() as usize;
+    // Every task gets a private copy of the program text
+    for (i, chunk) in program.chunks(PAGE_SIZE as usize).enumerate() {
+        let page = Page::containing_address((start + i * PAGE_SIZE as usize) as u64);
+        let frame = space
+            .map_alloc(page, EntryFlags::RWXU)
+            .expect("Failed to map user program");
 
-    page::map_range(
-        root,
-        TASK_BEGIN_VADDR as usize,
-        pstart,
-        1024 * 1024,
-        EntryFlags::RWXU,
-    );
+        let dst = page::phys_to_virt(frame.addr()).as_mut_ptr::<u8>();
+        unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), dst, chunk.len()) };
+    }
 
-    // Expose UART MMIO
-    page::map(
-        root,
-        Page::containing_address(0x10_0000_0000),
-        Frame::containing_address(0x10000000),
-        EntryFlags::RWU,
-    );
+    // The program is an area as well, so that its flags decide whether its
+    // pages may be written once they are copy-on-write. The rest of the
+    // task's memory is only backed once it is touched.
+    let text_end = (start + program.len()).next_multiple_of(PAGE_SIZE as usize);
+    space
+        .add_vma(start, text_end, EntryFlags::RWXU)
+        .expect("Failed to add user program area");
+    space
+        .add_vma(text_end, end, EntryFlags::RWU)
+        .expect("Failed to add user memory area");
 
-    unsafe { asm!("sfence.vma zero, zero") }
+    // Expose UART MMIO
+    space
+        .map(
+            Page::containing_address(0x10_0000_0000),
+            Frame::containing_address(0x10000000),
+            EntryFlags::RWU,
+        )
+        .expect("Failed to map UART");
 
     serial_debug!(
-        "Mapped user space memory: {:#x?} - {:#x?}",
-        TASK_BEGIN_VADDR,
-        TASK_BEGIN_VADDR + 1024 * 1024
+        "Mapped user space memory: {:#x?} - {:#x?}, demand paged from {:#x?}",
+        start,
+        end,
+        text_end
     );
 }
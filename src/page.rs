@@ -1,70 +1,163 @@
 extern crate alloc;
 
-use core::alloc::Layout;
-
-use alloc::{alloc::alloc_zeroed, boxed::Box};
+use hal_core::mmu::{PageTables, PhysMemory, Tlb};
 use hal_core::page::{
-    EntryFlags, Frame, FrameRange, Paddr, Page, PageRange, PageTable, PageTableEntry, Vaddr,
+    EntryFlags, Frame, FrameAllocator, Mapping, Mappings, Paddr, Page, PageRange, PageTable,
+    PageTableEntry, PagingMode, Vaddr, PAGE_SIZE,
 };
+use hal_riscv::cpu::{sfence_vma, sfence_vma_all, Satp};
+use once_cell::unsync::OnceCell;
 
+use crate::alloc::Locked;
+use crate::frame::{frame_refcount, release_frame, retain_frame, try_alloc_frame};
 use crate::serial_debug;
 
-fn map_to_frame(root: &mut PageTable, page: Page, frame: Frame, flags: EntryFlags) {
-    let vpn = page.addr().indexed_vpn();
-    let mut table = root;
-
-    for lv in (0..=2).rev() {
-        let index = vpn[lv];
-        let entry = table.entry_mut(index);
-
-        if entry.is_valid() {
-            if entry.is_leaf() {
-                // This address is already mapped, nothing to do
-                return;
-            }
-
-            let next_page_table_paddr = entry.paddr();
-            table = unsafe { &mut *next_page_table_paddr.as_mut_ptr::<PageTable>() };
-        } else {
-            if lv == 0 {
-                // Create a leaf entry and return
-                *entry = PageTableEntry::new(
-                    EntryFlags::Valid.as_u64()
-                        | EntryFlags::Accessed.as_u64()
-                        | EntryFlags::Dirty.as_u64()
-                        | flags.as_u64(),
-                );
-                entry.set_paddr(frame.addr());
-                return;
-            }
-
-            let next_page_table = PageTable::new();
-            let ptr = Box::into_raw(Box::new(next_page_table));
-            let next_page_table_paddr = Paddr::new(ptr as u64);
-
-            *entry = PageTableEntry::new(EntryFlags::Valid.as_u64());
-            entry.set_paddr(next_page_table_paddr);
-            table = unsafe { &mut *ptr };
-        }
+pub use hal_core::mmu::MapError;
+
+static PAGING_MODE: Locked<OnceCell<PagingMode>> = Locked::new(OnceCell::new());
+
+/// Selects the paging mode with the largest address space the hart
+/// supports, which the boot code left in `probed`. Must be called before any
+/// page table is built.
+pub fn init_paging_mode(probed: &Satp) -> PagingMode {
+    let mode = PagingMode::ALL
+        .into_iter()
+        .find(|mode| mode.satp_mode() == probed.mode())
+        .expect("Hart does not support any paging mode");
+
+    PAGING_MODE
+        .lock()
+        .set(mode)
+        .expect("Paging mode already initialized");
+
+    mode
+}
+
+pub fn paging_mode() -> PagingMode {
+    *PAGING_MODE
+        .lock()
+        .get()
+        .expect("Paging mode not initialized")
+}
+
+/// Offset of the direct map window from physical addresses. The window lies
+/// in the kernel half of every paging mode, after the kernel image.
+pub const DIRECT_MAP_OFFSET: u64 = 0xffff_ffe0_0000_0000;
+
+/// Address of `paddr` in the direct map window
+pub fn phys_to_virt(paddr: Paddr) -> Vaddr {
+    Vaddr::new(paddr.inner() + DIRECT_MAP_OFFSET)
+}
+
+/// Physical address of `vaddr`, which must lie in the direct map window
+pub fn virt_to_phys(vaddr: Vaddr) -> Paddr {
+    Paddr::new(vaddr.inner() - DIRECT_MAP_OFFSET)
+}
+
+/// Whether `vaddr` lies in the lower half, which belongs to user space.
+pub fn is_user_address(vaddr: usize) -> bool {
+    (vaddr as u64) < paging_mode().user_end()
+}
+
+/// Physical memory as the kernel sees it: through the direct map, with
+/// frames from the frame allocator and the TLB of this hart.
+#[derive(Debug, Default)]
+pub struct KernelMemory;
+
+impl PhysMemory for KernelMemory {
+    fn ptr(&self, paddr: Paddr) -> *mut u8 {
+        phys_to_virt(paddr).as_mut_ptr()
     }
 }
 
-pub fn allocate_root() -> &'static mut PageTable {
-    let root = PageTable::new();
-    let ptr = Box::into_raw(Box::new(root));
+impl FrameAllocator for KernelMemory {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        try_alloc_frame()
+    }
 
-    serial_debug!("Allocated L2 (root) page table at 0x{:x}", ptr as usize);
+    fn retain_frame(&mut self, frame: Frame) {
+        retain_frame(frame)
+    }
 
-    unsafe { &mut *ptr }
+    fn release_frame(&mut self, frame: Frame) -> bool {
+        release_frame(frame)
+    }
+
+    fn refcount(&self, frame: Frame) -> u16 {
+        frame_refcount(frame)
+    }
+}
+
+impl Tlb for KernelMemory {
+    fn flush_page(&mut self, vaddr: Vaddr) {
+        sfence_vma(vaddr.inner() as usize);
+    }
+
+    fn flush_all(&mut self) {
+        sfence_vma_all();
+    }
+}
+
+// Physical address of the root table `root`, which like every page table is
+// accessed through the direct map
+fn root_paddr(root: &PageTable) -> Paddr {
+    virt_to_phys(Vaddr::new(root as *const PageTable as u64))
+}
+
+// Page table hierarchy below `root` in the current paging mode
+fn tables<'a>(mem: &'a mut KernelMemory, root: &PageTable) -> PageTables<'a, KernelMemory> {
+    PageTables::new(mem, paging_mode(), root_paddr(root))
+}
+
+/// Every mapping of the hierarchy below `root`, see [`PageTable::mappings`].
+pub fn mappings(root: &PageTable) -> Mappings<'_, KernelMemory> {
+    Mappings::new(&KernelMemory, paging_mode(), root_paddr(root))
+}
+
+fn map_to_frame(
+    root: &mut PageTable,
+    page: Page,
+    frame: Frame,
+    flags: EntryFlags,
+) -> Result<(), MapError> {
+    tables(&mut KernelMemory, root).map(page, frame, flags)
+}
+
+/// Maps `size` bytes at `vstart` to `pstart`, using the largest pages that
+/// the alignment of both addresses and the remaining length allow. All three
+/// must be page aligned.
+pub fn map_region(
+    root: &mut PageTable,
+    vstart: u64,
+    pstart: u64,
+    size: u64,
+    flags: EntryFlags,
+) -> Result<(), MapError> {
+    tables(&mut KernelMemory, root).map_region(vstart, pstart, size, flags)
+}
+
+// Size of the range of pages from the one containing `start` up to and
+// including the one containing `end`.
+fn inclusive_size(start: u64, end: u64) -> u64 {
+    (end & !(PAGE_SIZE - 1)) + PAGE_SIZE - (start & !(PAGE_SIZE - 1))
 }
 
 pub fn id_map(root: &mut PageTable, page: Page, flags: EntryFlags) {
     let frame = Frame::containing_address(page.addr().inner());
-    map_to_frame(root, page, frame, flags);
+    map(root, page, frame, flags);
 }
 
 pub fn map(root: &mut PageTable, page: Page, frame: Frame, flags: EntryFlags) {
-    map_to_frame(root, page, frame, flags);
+    try_map(root, page, frame, flags).expect("Failed to map page");
+}
+
+pub fn try_map(
+    root: &mut PageTable,
+    page: Page,
+    frame: Frame,
+    flags: EntryFlags,
+) -> Result<(), MapError> {
+    map_to_frame(root, page, frame, flags)
 }
 
 pub fn map_range(
@@ -74,68 +167,209 @@ pub fn map_range(
     size: usize,
     flags: EntryFlags,
 ) {
-    let vrange = PageRange::new(
-        Vaddr::new(vstart as u64),
-        Vaddr::new((vstart + size) as u64),
-    );
-    let prange = FrameRange::new(
-        Paddr::new(pstart as u64),
-        Paddr::new((pstart + size) as u64),
-    );
-    let range = vrange.zip(prange);
-    for (page, frame) in range {
-        map(root, page, frame, flags.clone());
-    }
+    try_map_range(root, vstart, pstart, size, flags).expect("Failed to map range");
+}
+
+/// Uses megapages and gigapages where `vstart`, `pstart` and `size` allow.
+/// Pages mapped before a failure stay mapped.
+pub fn try_map_range(
+    root: &mut PageTable,
+    vstart: usize,
+    pstart: usize,
+    size: usize,
+    flags: EntryFlags,
+) -> Result<(), MapError> {
+    let vstart = vstart as u64;
+    let pstart = pstart as u64;
+    let size = inclusive_size(vstart, vstart + size as u64);
+
+    map_region(
+        root,
+        vstart & !(PAGE_SIZE - 1),
+        pstart & !(PAGE_SIZE - 1),
+        size,
+        flags,
+    )
 }
 
 pub fn map_alloc(root: &mut PageTable, page: Page, flags: EntryFlags) {
-    let layout = Layout::from_size_align(4096, 4096).expect("Invalid layout");
-    let frame = unsafe { alloc_zeroed(layout) };
-    map_to_frame(root, page, Frame::containing_address(frame as u64), flags);
+    try_map_alloc(root, page, flags).expect("Failed to map page");
+}
+
+pub fn try_map_alloc(root: &mut PageTable, page: Page, flags: EntryFlags) -> Result<(), MapError> {
+    let frame = try_alloc_frame().ok_or(MapError::OutOfFrames)?;
+    map_to_frame(root, page, frame, flags).inspect_err(|_| {
+        release_frame(frame);
+    })
 }
 
 pub fn map_alloc_range(root: &mut PageTable, start: usize, end: usize, flags: EntryFlags) {
+    try_map_alloc_range(root, start, end, flags).expect("Failed to map range");
+}
+
+/// Pages mapped before a failure stay mapped.
+pub fn try_map_alloc_range(
+    root: &mut PageTable,
+    start: usize,
+    end: usize,
+    flags: EntryFlags,
+) -> Result<(), MapError> {
     let start = Vaddr::new(start as u64);
     let end = Vaddr::new(end as u64);
 
     let range = PageRange::new(start, end);
     for page in range {
-        map_alloc(root, page, flags.clone());
+        try_map_alloc(root, page, flags)?;
     }
+
+    Ok(())
 }
 
+/// Uses megapages and gigapages where the range allows.
 pub fn id_map_range(root: &mut PageTable, start: usize, end: usize, flags: EntryFlags) {
-    let start = Vaddr::new(start as u64);
-    let end = Vaddr::new(end as u64);
+    offset_map_range(root, start, end, 0, flags);
+}
 
-    let range = PageRange::new(start, end);
-    for page in range {
-        id_map(root, page, flags.clone());
-    }
+/// Maps the physical pages from the one containing `start` up to and
+/// including the one containing `end` at their address plus `offset`, which
+/// must be page aligned.
+pub fn offset_map_range(
+    root: &mut PageTable,
+    start: usize,
+    end: usize,
+    offset: u64,
+    flags: EntryFlags,
+) {
+    let start = start as u64 & !(PAGE_SIZE - 1);
+    let size = inclusive_size(start, end as u64);
+    let vstart = start.wrapping_add(offset);
+
+    map_region(root, vstart, start, size, flags).expect("Failed to map range");
+}
+
+/// Removes the mapping of `page` and returns the frame it was mapped to.
+/// Superpages around the page are split, which needs a frame for the new
+/// table.
+pub fn unmap(root: &mut PageTable, page: Page) -> Result<Option<Frame>, MapError> {
+    tables(&mut KernelMemory, root).unmap(page)
+}
+
+/// Removes every mapping in `start..end` and passes the frames that were
+/// mapped there to `unmapped`. Both addresses must be page aligned. Fails if
+/// a superpage that lies partly in the range cannot be split, in which case
+/// the pages before it have already been removed.
+pub fn unmap_range(
+    root: &mut PageTable,
+    start: usize,
+    end: usize,
+    unmapped: impl FnMut(Frame),
+) -> Result<(), MapError> {
+    tables(&mut KernelMemory, root).unmap_range(start as u64, end as u64, unmapped)
 }
 
-pub fn translate_vaddr(root: &mut PageTable, vaddr: Vaddr) -> Option<Paddr> {
-    let vpn = vaddr.indexed_vpn();
-    let mut table = root;
+/// Changes the flags of every page in `start..end`, keeping the software
+/// bits of the entries. Both addresses must be page aligned. Fails if a page
+/// in the range is not mapped, in which case the pages before it have
+/// already been changed.
+pub fn protect(
+    root: &mut PageTable,
+    start: usize,
+    end: usize,
+    flags: EntryFlags,
+) -> Result<(), MapError> {
+    tables(&mut KernelMemory, root).protect(start as u64, end as u64, flags)
+}
+
+/// Changes the flags of the pages mapped in `start..end`. Owned frames that
+/// are shared with other address spaces stay copy-on-write.
+pub fn protect_cow(
+    root: &mut PageTable,
+    start: usize,
+    end: usize,
+    flags: EntryFlags,
+) -> Result<(), MapError> {
+    tables(&mut KernelMemory, root).protect_cow(start as u64, end as u64, flags)
+}
+
+/// Maps everything in `start..end` into `child` as well, sharing owned frames
+/// and making writable ones copy-on-write in both.
+pub fn share_cow(root: &mut PageTable, child: Paddr, start: u64, end: u64) -> Result<(), MapError> {
+    tables(&mut KernelMemory, root).share_cow(child, start, end)
+}
+
+/// Gives the copy-on-write page `page` a writable frame of its own.
+pub fn break_cow(root: &mut PageTable, page: Page) -> Result<(), MapError> {
+    tables(&mut KernelMemory, root).break_cow(page)
+}
+
+/// Maps `page` to `frame`, replacing any existing mapping, and returns the
+/// frame that was mapped before.
+pub fn remap(
+    root: &mut PageTable,
+    page: Page,
+    frame: Frame,
+    flags: EntryFlags,
+) -> Result<Option<Frame>, MapError> {
+    tables(&mut KernelMemory, root).remap(page, frame, flags)
+}
+
+/// Checks that the physical pages from the one containing `start` up to and
+/// including the one containing `end` are mapped at their address plus
+/// `offset` with at least `flags`. Returns the first virtual address for
+/// which that does not hold.
+pub fn check_offset_mapped(
+    root: &PageTable,
+    start: usize,
+    end: usize,
+    offset: u64,
+    flags: EntryFlags,
+) -> Result<(), Vaddr> {
+    let start = (start as u64 & !(PAGE_SIZE - 1)).wrapping_add(offset);
+    let end = start + inclusive_size(start, (end as u64).wrapping_add(offset));
 
-    for lv in (0..=2).rev() {
-        let index = vpn[lv];
-        let entry = table.entry_mut(index);
-        if !entry.is_valid() {
-            serial_debug!("Entry is not valid: LV: {}, INDEX: {}", lv, index);
-            return None;
+    let mut vaddr = start;
+    for mapping in mappings(root) {
+        if vaddr >= end {
+            break;
+        }
+        if mapping.vend() <= vaddr {
+            continue;
         }
 
-        if entry.is_leaf() {
-            let frame = entry.paddr();
-            let paddr = frame.inner() | vaddr.offset();
-            return Some(Paddr::new(paddr));
+        if mapping.vstart > vaddr
+            || mapping.vstart.wrapping_sub(mapping.pstart) != offset
+            || !mapping.flags.contains(flags)
+        {
+            return Err(Vaddr::new(vaddr));
         }
+        vaddr = mapping.vend();
+    }
 
-        let next_page_table_paddr = entry.paddr();
-        table = unsafe { &mut *next_page_table_paddr.as_mut_ptr::<PageTable>() };
+    if vaddr < end {
+        return Err(Vaddr::new(vaddr));
     }
+    Ok(())
+}
 
-    serial_debug!("This code must not be reached");
-    None
+/// Checks that no kernel page is both writable and executable. Returns the
+/// first mapping that is.
+pub fn check_wx(root: &PageTable) -> Result<(), Mapping> {
+    let wx = EntryFlags::WRITE | EntryFlags::EXECUTE;
+
+    mappings(root)
+        .find(|mapping| mapping.flags.contains(wx) && !mapping.flags.contains(EntryFlags::USER))
+        .map_or(Ok(()), Err)
+}
+
+/// Leaf entry that maps `vaddr` and its level, if any.
+pub fn lookup(root: &PageTable, vaddr: Vaddr) -> Option<(PageTableEntry, usize)> {
+    tables(&mut KernelMemory, root).lookup(vaddr)
+}
+
+pub fn translate_vaddr(root: &PageTable, vaddr: Vaddr) -> Option<Paddr> {
+    let paddr = tables(&mut KernelMemory, root).translate(vaddr);
+    if paddr.is_none() {
+        serial_debug!("0x{:x} is not mapped", vaddr.inner());
+    }
+    paddr
 }
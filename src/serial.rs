@@ -5,6 +5,8 @@ use core::{
 use owo_colors::OwoColorize;
 use spin::Mutex;
 
+use crate::page::DIRECT_MAP_OFFSET;
+
 const UART_MMIO_ADDR: usize = 0x10000000;
 
 const INFO: &str = "INFO";
@@ -13,7 +15,7 @@ const ERROR: &str = "ERROR";
 const PATHOS: &str = "PathOS";
 
 struct Serial(usize);
-static SERIAL: Mutex<Serial> = Mutex::new(Serial(UART_MMIO_ADDR));
+static SERIAL: Mutex<Serial> = Mutex::new(Serial(DIRECT_MAP_OFFSET as usize + UART_MMIO_ADDR));
 
 impl fmt::Write for Serial {
     fn write_str(&mut self, s: &str) -> fmt::Result {
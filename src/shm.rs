@@ -0,0 +1,98 @@
+extern crate alloc;
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use hal_core::page::{Frame, PAGE_SIZE};
+
+use crate::alloc::Locked;
+use crate::frame::{release_frame, try_alloc_frame};
+use crate::page::MapError;
+use crate::serial_debug;
+
+/// Name of a shared memory object, chosen by whoever creates it. Tasks that
+/// agree on a name share the object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShmId(usize);
+
+impl ShmId {
+    pub fn new(id: usize) -> Self {
+        Self(id)
+    }
+
+    pub fn inner(&self) -> usize {
+        self.0
+    }
+}
+
+/// Zeroed frames that several address spaces can map, each at its own
+/// address and with its own flags. Address spaces hold a reference per
+/// mapping, and the frames are freed with the last one.
+#[derive(Debug)]
+pub struct SharedMemory {
+    id: ShmId,
+    frames: Vec<Frame>,
+}
+
+impl SharedMemory {
+    pub fn id(&self) -> ShmId {
+        self.id
+    }
+
+    /// Size in bytes, a multiple of the page size
+    pub fn size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE as usize
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        serial_debug!("Freeing shared memory {}", self.id.0);
+        for &frame in &self.frames {
+            release_frame(frame);
+        }
+    }
+}
+
+// Objects that may still be alive. Entries of freed objects are dropped when
+// the next object is opened.
+static REGISTRY: Locked<Vec<(ShmId, Weak<SharedMemory>)>> = Locked::new(Vec::new());
+
+/// Opens the live object named `id`, or creates it with `size` bytes, rounded
+/// up to whole pages, if there is none. A new object is freed again when the
+/// returned reference is dropped, unless the object was mapped or opened
+/// again before.
+pub fn open(id: ShmId, size: usize) -> Result<Arc<SharedMemory>, MapError> {
+    let mut registry = REGISTRY.lock();
+    registry.retain(|(_, object)| object.strong_count() > 0);
+
+    let live = registry
+        .iter()
+        .find(|(object_id, _)| *object_id == id)
+        .and_then(|(_, object)| object.upgrade());
+    if let Some(object) = live {
+        return Ok(object);
+    }
+
+    // Not reserved up front, the frames run out long before huge sizes
+    let pages = size.div_ceil(PAGE_SIZE as usize);
+    let mut frames = Vec::new();
+    for _ in 0..pages {
+        let Some(frame) = try_alloc_frame() else {
+            for frame in frames {
+                release_frame(frame);
+            }
+            return Err(MapError::OutOfFrames);
+        };
+        frames.push(frame);
+    }
+
+    let object = Arc::new(SharedMemory { id, frames });
+    registry.push((id, Arc::downgrade(&object)));
+
+    serial_debug!("Created shared memory {} of {} pages", id.0, pages);
+    Ok(object)
+}
@@ -0,0 +1,93 @@
+extern crate alloc;
+
+use core::alloc::Layout;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use hal_core::page::{EntryFlags, Frame, Page, PAGE_SIZE};
+
+use crate::address_space;
+
+/// Pages of a kernel stack, not counting its guard page
+const KERNEL_STACK_PAGES: usize = 7;
+
+// The running task and the guard page of its kernel stack. Atomics, so that
+// the trap stack handler can read them without waiting for a lock that the
+// overflowing code may hold.
+static CURRENT_TASK: AtomicUsize = AtomicUsize::new(0);
+static CURRENT_GUARD: AtomicUsize = AtomicUsize::new(0);
+
+/// Stack that the traps of a task are handled on. The page below it is a
+/// guard, which is unmapped from the kernel half as long as the stack lives.
+#[derive(Debug)]
+pub struct KernelStack {
+    // Start of the guard page
+    base: usize,
+    // Heap memory behind the guard page, mapped again when the stack is freed
+    guard_frame: Frame,
+}
+
+impl KernelStack {
+    pub fn new() -> Self {
+        let layout = Self::layout();
+        let base = unsafe { alloc::alloc::alloc(layout) };
+        if base.is_null() {
+            alloc::alloc::handle_alloc_error(layout);
+        }
+
+        let guard = Page::containing_address(base as u64);
+        let guard_frame = address_space::unmap_kernel_page(guard)
+            .expect("Failed to unmap kernel stack guard")
+            .expect("Kernel stack is not mapped");
+
+        Self {
+            base: base as usize,
+            guard_frame,
+        }
+    }
+
+    fn layout() -> Layout {
+        let size = (KERNEL_STACK_PAGES + 1) * PAGE_SIZE as usize;
+        Layout::from_size_align(size, PAGE_SIZE as usize).expect("Invalid kernel stack layout")
+    }
+
+    pub fn guard(&self) -> Range<usize> {
+        self.base..self.base + PAGE_SIZE as usize
+    }
+
+    /// Initial stack pointer
+    pub fn top(&self) -> usize {
+        self.base + Self::layout().size()
+    }
+}
+
+impl Default for KernelStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        // The heap maps all of its memory like this
+        let guard = Page::containing_address(self.base as u64);
+        let flags = EntryFlags::RW | EntryFlags::GLOBAL;
+        address_space::map_kernel_page(guard, self.guard_frame, flags)
+            .expect("Failed to map kernel stack guard");
+
+        unsafe { alloc::alloc::dealloc(self.base as *mut u8, Self::layout()) }
+    }
+}
+
+/// Records that task `tid` is about to run on `stack`.
+pub fn set_current(tid: usize, stack: &KernelStack) {
+    CURRENT_TASK.store(tid, Ordering::Relaxed);
+    CURRENT_GUARD.store(stack.guard().start, Ordering::Relaxed);
+}
+
+/// Task whose kernel stack overflowed, if `addr` lies in its guard page
+pub fn overflowed_task(addr: usize) -> Option<usize> {
+    let guard = CURRENT_GUARD.load(Ordering::Relaxed);
+    (guard != 0 && (guard..guard + PAGE_SIZE as usize).contains(&addr))
+        .then(|| CURRENT_TASK.load(Ordering::Relaxed))
+}
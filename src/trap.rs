@@ -1,14 +1,25 @@
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::arch::asm;
 
 use hal_core::page::Vaddr;
 use once_cell::unsync::OnceCell;
 
-use crate::{alloc::Locked, KERNEL_STACK_END};
+use crate::alloc::{Locked, TaskAllocator};
+use crate::shm::SharedMemory;
+use crate::{address_space::AddressSpace, stack::KernelStack};
 
 #[derive(Debug)]
 pub struct Scheduler {
-    tasks: [Task; 3],
+    // Terminated tasks leave an empty slot, so task ids stay stable
+    tasks: [Option<Box<Task, TaskAllocator>>; 3],
     current: usize,
+    // Kernel stack of the last terminated task, which the trap handler may
+    // still be running on
+    exited: Option<KernelStack>,
 }
 
 pub static SCHEDULER: Locked<OnceCell<Scheduler>> = Locked::new(OnceCell::new());
@@ -48,18 +59,33 @@ pub struct TrapFrame {
     kernel_sp: usize,
 }
 
+impl TrapFrame {
+    /// Number and arguments of an ecall made by a user program
+    pub fn user_ecall(&self) -> (u64, [u64; 3]) {
+        (self.t5, [self.a0, self.a1, self.a2])
+    }
+
+    pub fn set_return_value(&mut self, value: u64) {
+        self.a0 = value;
+    }
+}
+
 #[derive(Debug)]
 pub struct Task {
     pub trap_frame: TrapFrame,
     addr: Vaddr,
     pub pc: Vaddr,
+    pub address_space: AddressSpace,
+    pub kernel_stack: KernelStack,
+    /// Shared memory objects the task has open
+    pub shared_memory: Vec<Arc<SharedMemory>>,
 }
 
 impl Task {
-    pub fn new(addr: Vaddr, tid: u64) -> Self {
-        let kernel_sp = unsafe { KERNEL_STACK_END };
+    pub fn new(addr: Vaddr, tid: u64, address_space: AddressSpace) -> Self {
+        let kernel_stack = KernelStack::new();
         let trap_frame = TrapFrame {
-            kernel_sp,
+            kernel_sp: kernel_stack.top(),
             a0: tid,
             ..TrapFrame::default()
         };
@@ -67,6 +93,9 @@ impl Task {
             trap_frame,
             addr,
             pc: addr,
+            address_space,
+            kernel_stack,
+            shared_memory: Vec::new(),
         }
     }
 }
@@ -74,7 +103,11 @@ impl Task {
 impl Scheduler {
     #[inline(always)]
     pub fn new(tasks: [Task; 3]) -> Self {
-        Self { tasks, current: 0 }
+        Self {
+            tasks: tasks.map(|task| Some(Box::new_in(task, TaskAllocator))),
+            current: 0,
+            exited: None,
+        }
     }
 
     pub fn current(&self) -> usize {
@@ -83,21 +116,49 @@ impl Scheduler {
 
     #[inline(always)]
     pub fn task(&self, tid: usize) -> &Task {
-        self.tasks.get(tid).expect("Invalid task index")
+        self.tasks
+            .get(tid)
+            .and_then(Option::as_deref)
+            .expect("Invalid task index")
+    }
+
+    pub fn task_mut(&mut self, tid: usize) -> &mut Task {
+        self.tasks
+            .get_mut(tid)
+            .and_then(Option::as_deref_mut)
+            .expect("Invalid task index")
     }
 
     pub fn save_state(&mut self, addr: u64) {
-        let state = self
+        let state = self.task_mut(self.current);
+        state.pc = Vaddr::new(addr);
+    }
+
+    /// Removes a task from the scheduler. Dropping the returned address
+    /// space frees its memory. The kernel stack is kept until the next task
+    /// terminates, because the caller may be running on it.
+    pub fn terminate(&mut self, tid: usize) -> AddressSpace {
+        let task = *self
             .tasks
-            .get_mut(self.current)
+            .get_mut(tid)
+            .and_then(Option::take)
             .expect("Invalid task index");
-        state.pc = Vaddr::new(addr);
+
+        self.exited = Some(task.kernel_stack);
+        task.address_space
     }
 
+    /// Switches to the next task that has not terminated, which may be the
+    /// current one. Returns `None` once all tasks are gone.
     #[inline(always)]
-    pub fn next(&mut self) -> (usize, &Task) {
-        self.current = (self.current + 1) % self.tasks.len();
-        (self.current, &self.tasks[self.current])
+    pub fn next(&mut self) -> Option<(usize, &Task)> {
+        let len = self.tasks.len();
+        let tid = (1..=len)
+            .map(|offset| (self.current + offset) % len)
+            .find(|&tid| self.tasks[tid].is_some())?;
+
+        self.current = tid;
+        Some((tid, self.task(tid)))
     }
 }
 
@@ -132,7 +193,7 @@ pub unsafe fn save_cpu_registers() {
         "sd a5, 208(a0)",
         "sd a6, 216(a0)",
         "sd a7, 224(a0)",
-        "csrr t0, mscratch",
+        "csrr t0, sscratch",
         "sd t0, 168(a0)",
         "mv t0, zero",
         "ret",